@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS siren_checks (
+    id bigserial PRIMARY KEY,
+    siren_id bigint NOT NULL REFERENCES sirens ON DELETE CASCADE,
+    check_date date NOT NULL,
+    contact_id bigint REFERENCES contacts ON DELETE SET NULL,
+    result text,
+    note text,
+    created_at timestamp without time zone DEFAULT now(),
+    updated_at timestamp without time zone DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS siren_checks_siren_id_idx ON siren_checks (siren_id, check_date DESC);
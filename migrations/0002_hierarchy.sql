@@ -0,0 +1,7 @@
+ALTER TABLE companies ADD COLUMN IF NOT EXISTS parent_id bigint REFERENCES companies ON DELETE SET NULL;
+
+ALTER TABLE departments ADD COLUMN IF NOT EXISTS parent_id bigint REFERENCES departments ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS companies_parent_id_idx ON companies (parent_id);
+
+CREATE INDEX IF NOT EXISTS departments_parent_id_idx ON departments (parent_id);
@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS attachments (
+    id bigserial PRIMARY KEY,
+    entity text NOT NULL,
+    entity_id bigint NOT NULL,
+    filename text NOT NULL,
+    content_type text NOT NULL,
+    size bigint NOT NULL,
+    hash text NOT NULL,
+    thumbnail boolean NOT NULL DEFAULT false,
+    user_id bigint,
+    created_at timestamp without time zone DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS attachments_entity_idx ON attachments (entity, entity_id);
+
+CREATE INDEX IF NOT EXISTS attachments_hash_idx ON attachments (hash);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS contact_photos (
+    contact_id bigint PRIMARY KEY REFERENCES contacts ON DELETE CASCADE,
+    hash text NOT NULL,
+    updated_at timestamp without time zone DEFAULT now()
+);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS entity_versions (
+    id bigserial PRIMARY KEY,
+    entity text NOT NULL,
+    entity_id bigint NOT NULL,
+    version integer NOT NULL,
+    data jsonb NOT NULL,
+    created_at timestamp without time zone NOT NULL DEFAULT now(),
+    UNIQUE (entity, entity_id, version)
+);
+
+CREATE INDEX IF NOT EXISTS entity_versions_created_at_idx ON entity_versions (entity, entity_id, created_at);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS user_scopes (
+    user_id bigint PRIMARY KEY REFERENCES users ON DELETE CASCADE,
+    company_id bigint REFERENCES companies ON DELETE CASCADE,
+    department_id bigint REFERENCES departments ON DELETE CASCADE
+);
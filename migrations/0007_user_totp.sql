@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS user_totp (
+    user_id bigint PRIMARY KEY REFERENCES users ON DELETE CASCADE,
+    secret text NOT NULL,
+    enabled boolean NOT NULL DEFAULT false,
+    recovery_codes text[] NOT NULL DEFAULT '{}',
+    last_step bigint NOT NULL DEFAULT 0,
+    created_at timestamp without time zone DEFAULT now()
+);
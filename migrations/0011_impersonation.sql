@@ -0,0 +1,3 @@
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS impersonator_id bigint REFERENCES users ON DELETE SET NULL;
+
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS expires_at timestamp without time zone;
@@ -0,0 +1,21 @@
+-- Names were not unique before, keep the oldest user under each name and
+-- rename the others so the index can be built; each rename is reported.
+DO $$
+DECLARE
+    dup record;
+BEGIN
+    FOR dup IN
+        SELECT id, name FROM (
+            SELECT id, name, row_number() OVER (PARTITION BY name ORDER BY id) AS n
+            FROM users
+        ) AS u
+        WHERE n > 1
+    LOOP
+        UPDATE users SET name = dup.name || ' (' || dup.id || ')' WHERE id = dup.id;
+        RAISE WARNING 'duplicate user name % of user % renamed to %',
+            dup.name, dup.id, dup.name || ' (' || dup.id || ')';
+    END LOOP;
+END
+$$;
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_name_idx ON users (name);
@@ -0,0 +1,391 @@
+use std::collections::HashSet;
+
+use rpel::RpelPool;
+use serde_json::Value;
+
+use crate::{
+    attachment::{Attachment, AttachmentObject},
+    dbo::{get_list, DbObject},
+    error::ServiceError,
+    messages::{Command, Item},
+    pdf::PdfObject,
+    users::UserData,
+};
+
+const CONTACTS: &str = "
+    SELECT c.id FROM contacts AS c
+    WHERE ($1::bigint IS NULL OR c.company_id = $1)
+    AND ($2::bigint IS NULL OR c.department_id = $2)";
+
+/// Shared reference data, readable by everyone and writable only without a scope.
+const REFERENCE: &[&str] = &[
+    "Department",
+    "DepartmentList",
+    "DepartmentSelect",
+    "DepartmentTree",
+    "DepartmentPath",
+    "Kind",
+    "KindList",
+    "KindSelect",
+    "Post",
+    "PostList",
+    "PostSelect",
+    "PostGoSelect",
+    "Rank",
+    "RankList",
+    "RankSelect",
+    "Scope",
+    "ScopeList",
+    "ScopeSelect",
+    "SirenType",
+    "SirenTypeList",
+    "SirenTypeSelect",
+    "User",
+    "UserList",
+];
+
+/// Row-level limits of a user. Without company and department everything
+/// is allowed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Access {
+    pub company_id: Option<i64>,
+    pub department_id: Option<i64>,
+}
+
+/// Entity a list consists of and the field holding its id.
+fn list_entity(name: &str) -> Option<(&'static str, &'static str)> {
+    match name {
+        "BirthdayNear" | "ContactList" | "ContactSelect" => Some(("Contact", "id")),
+        "CertificateList" => Some(("Certificate", "id")),
+        "CompanyList" | "CompanySelect" => Some(("Company", "id")),
+        "EducationList" | "EducationNear" | "EducationShort" => Some(("Education", "id")),
+        "PracticeList" | "PracticeNear" | "PracticeShort" => Some(("Practice", "id")),
+        "SirenList" => Some(("Siren", "id")),
+        "SirenCheckList" => Some(("SirenCheck", "id")),
+        "SirenOverdue" => Some(("Siren", "siren_id")),
+        _ => None,
+    }
+}
+
+/// Entity whose id is used by `GetItem`.
+fn item_entity(name: &str) -> &str {
+    match name {
+        "CompanyPath" | "CompanyTree" => "Company",
+        "SirenCheckHistory" => "Siren",
+        name => name,
+    }
+}
+
+fn ids_query(entity: &str) -> Option<String> {
+    let query = match entity {
+        "Contact" => CONTACTS.to_string(),
+        // A department scope allows the companies with contacts of it.
+        "Company" => "SELECT co.id FROM companies AS co
+            WHERE ($1::bigint IS NULL OR co.id = $1)
+            AND (
+                $2::bigint IS NULL
+                OR EXISTS (
+                    SELECT 1 FROM contacts AS c
+                    WHERE c.company_id = co.id AND c.department_id = $2
+                )
+            )"
+        .to_string(),
+        "Practice" => format!(
+            "SELECT p.id FROM practices AS p WHERE p.company_id IN ({})",
+            ids_query("Company")?
+        ),
+        "Certificate" => format!(
+            "SELECT ce.id FROM certificates AS ce
+            WHERE ce.contact_id IN ({CONTACTS})
+            OR ($1::bigint IS NOT NULL AND ce.contact_id IS NULL AND ce.company_id = $1)"
+        ),
+        "Education" => {
+            format!("SELECT e.id FROM educations AS e WHERE e.contact_id IN ({CONTACTS})")
+        }
+        "Siren" => format!(
+            "SELECT s.id FROM sirens AS s
+            WHERE ($1::bigint IS NOT NULL AND s.company_id = $1)
+            OR s.contact_id IN ({CONTACTS})"
+        ),
+        "SirenCheck" => format!(
+            "SELECT sc.id FROM siren_checks AS sc WHERE sc.siren_id IN ({})",
+            ids_query("Siren")?
+        ),
+        _ => return None,
+    };
+    Some(query)
+}
+
+fn field(fields: &Value, name: &str) -> Option<i64> {
+    fields.get(name).and_then(Value::as_i64)
+}
+
+impl From<&UserData> for Access {
+    fn from(user: &UserData) -> Self {
+        Access {
+            company_id: user.company_id,
+            department_id: user.department_id,
+        }
+    }
+}
+
+impl Access {
+    pub fn is_limited(&self) -> bool {
+        self.company_id.is_some() || self.department_id.is_some()
+    }
+
+    async fn allowed_ids(
+        &self,
+        pool: &RpelPool,
+        entity: &str,
+    ) -> Result<HashSet<i64>, ServiceError> {
+        let query = ids_query(entity).ok_or(ServiceError::NotPermission)?;
+        let client = pool.get().await?;
+        Ok(client
+            .query(query.as_str(), &[&self.company_id, &self.department_id])
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    async fn allows(&self, pool: &RpelPool, entity: &str, id: i64) -> Result<bool, ServiceError> {
+        if !self.is_limited() || REFERENCE.contains(&entity) {
+            return Ok(true);
+        }
+        let Some(query) = ids_query(entity) else {
+            return Ok(false);
+        };
+        let client = pool.get().await?;
+        Ok(client
+            .query_opt(
+                format!("SELECT id FROM ({query}) AS allowed WHERE id = $3").as_str(),
+                &[&self.company_id, &self.department_id, &id],
+            )
+            .await?
+            .is_some())
+    }
+
+    async fn allows_opt(
+        &self,
+        pool: &RpelPool,
+        entity: &str,
+        id: Option<i64>,
+    ) -> Result<bool, ServiceError> {
+        match id {
+            Some(id) => self.allows(pool, entity, id).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Whether the state of an inserted or updated object stays in scope,
+    /// so an object can not be moved out of it.
+    async fn fits(&self, pool: &RpelPool, object: &DbObject) -> Result<bool, ServiceError> {
+        let name = object.name();
+        let fields = serde_json::to_value(object)?
+            .get_mut(&name)
+            .map(Value::take)
+            .unwrap_or_default();
+        let own_company =
+            self.company_id.is_some() && field(&fields, "company_id") == self.company_id;
+        let company_ok = self.company_id.is_none() || own_company;
+        Ok(match name.as_str() {
+            "Company" => {
+                self.allows_opt(pool, "Company", field(&fields, "id"))
+                    .await?
+            }
+            "Contact" => {
+                company_ok
+                    && (self.department_id.is_none()
+                        || field(&fields, "department_id") == self.department_id)
+            }
+            "Practice" => company_ok,
+            "Certificate" | "Siren" => {
+                own_company
+                    || self
+                        .allows_opt(pool, "Contact", field(&fields, "contact_id"))
+                        .await?
+            }
+            "Education" => {
+                self.allows_opt(pool, "Contact", field(&fields, "contact_id"))
+                    .await?
+            }
+            "SirenCheck" => {
+                self.allows_opt(pool, "Siren", field(&fields, "siren_id"))
+                    .await?
+            }
+            _ => false,
+        })
+    }
+
+    pub async fn check_entity(
+        &self,
+        pool: &RpelPool,
+        entity: &str,
+        entity_id: i64,
+    ) -> Result<(), ServiceError> {
+        self.require(self.allows(pool, entity, entity_id).await?)
+    }
+
+    fn require(&self, allowed: bool) -> Result<(), ServiceError> {
+        if allowed {
+            Ok(())
+        } else {
+            Err(ServiceError::NotPermission)
+        }
+    }
+
+    /// Denies commands touching rows out of scope. Lists are filtered by
+    /// `get_list` instead, views over all data are denied.
+    pub async fn check_command(
+        &self,
+        pool: &RpelPool,
+        command: &Command,
+    ) -> Result<(), ServiceError> {
+        if !self.is_limited() {
+            return Ok(());
+        }
+        let allowed = match command {
+            Command::GetItem(item) | Command::GetHistory(item) | Command::GetItemAt(item, _) => {
+                self.allows(pool, item_entity(&item.name), item.id).await?
+            }
+            Command::RevertItem(item, _) => {
+                !REFERENCE.contains(&item.name.as_str())
+                    && self.allows(pool, &item.name, item.id).await?
+            }
+            Command::GetList(name) => {
+                REFERENCE.contains(&name.as_str()) || list_entity(name).is_some()
+            }
+            Command::InsertItem(object) => self.fits(pool, object).await?,
+            Command::UpdateItem(object) => {
+                let item = Item {
+                    name: object.name(),
+                    id: serde_json::to_value(object)?
+                        .get(object.name())
+                        .and_then(|fields| field(fields, "id"))
+                        .unwrap_or_default(),
+                };
+                !REFERENCE.contains(&item.name.as_str())
+                    && self.allows(pool, &item.name, item.id).await?
+                    && self.fits(pool, object).await?
+            }
+            Command::DeleteItem(item) => {
+                !REFERENCE.contains(&item.name.as_str())
+                    && item.name != "Company"
+                    && self.allows(pool, &item.name, item.id).await?
+            }
+            Command::User(_) | Command::Totp(_) => true,
+            Command::Pdf(PdfObject::Contact(id)) => self.allows(pool, "Contact", *id).await?,
+            Command::Pdf(PdfObject::Company(id)) => self.allows(pool, "Company", *id).await?,
+            Command::Pdf(PdfObject::Practice(id)) => self.allows(pool, "Practice", *id).await?,
+            Command::OrgChart(params) => self.allows(pool, "Company", params.company_id).await?,
+            Command::MergeContacts { keep, remove } => {
+                self.allows(pool, "Contact", *keep).await?
+                    && self.allows(pool, "Contact", *remove).await?
+            }
+            Command::Attachment(AttachmentObject::GetAttachments { entity, entity_id }) => {
+                self.allows(pool, entity, *entity_id).await?
+            }
+            Command::Attachment(
+                AttachmentObject::GetAttachment(id)
+                | AttachmentObject::GetThumbnail(id)
+                | AttachmentObject::DeleteAttachment(id),
+            ) => {
+                let attachment = Attachment::get(pool, *id).await?;
+                self.allows(pool, &attachment.entity, attachment.entity_id)
+                    .await?
+            }
+            Command::Geo(_)
+            | Command::Dashboard
+            | Command::Report(_)
+            | Command::FindDuplicates(_) => false,
+        };
+        self.require(allowed)
+    }
+
+    /// `get_list` keeping only rows in scope.
+    pub async fn get_list(&self, name: &str, pool: &RpelPool) -> Result<DbObject, ServiceError> {
+        let list = get_list(name, pool).await?;
+        if !self.is_limited() || REFERENCE.contains(&name) {
+            return Ok(list);
+        }
+        let (entity, id_field) = list_entity(name).ok_or(ServiceError::NotPermission)?;
+        let allowed = self.allowed_ids(pool, entity).await?;
+        let list_name = list.name();
+        let mut value = serde_json::to_value(list)?;
+        if let Some(Value::Array(rows)) = value.get_mut(&list_name) {
+            rows.retain(|row| matches!(field(row, id_field), Some(id) if allowed.contains(&id)));
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `allowed_ids` binds both scope params and `allows` adds `$3`, so
+    /// every query has to use both with a type.
+    fn assert_typed(entity: &str) {
+        let query = ids_query(entity).unwrap();
+        assert!(query.contains("$1::bigint"), "{entity}");
+        assert!(query.contains("$2::bigint"), "{entity}");
+        assert!(!query.contains("$3"), "{entity}");
+    }
+
+    #[test]
+    fn contact_query_types_both_params() {
+        assert_typed("Contact");
+    }
+
+    #[test]
+    fn company_query_types_both_params() {
+        assert_typed("Company");
+    }
+
+    #[test]
+    fn practice_query_types_both_params() {
+        assert_typed("Practice");
+    }
+
+    #[test]
+    fn certificate_query_types_both_params() {
+        assert_typed("Certificate");
+    }
+
+    #[test]
+    fn education_query_types_both_params() {
+        assert_typed("Education");
+    }
+
+    #[test]
+    fn siren_query_types_both_params() {
+        assert_typed("Siren");
+    }
+
+    #[test]
+    fn siren_check_query_types_both_params() {
+        assert_typed("SirenCheck");
+    }
+
+    #[test]
+    fn lists_and_items_have_queries() {
+        for name in [
+            "ContactList",
+            "CompanyList",
+            "PracticeList",
+            "CertificateList",
+            "EducationList",
+            "SirenList",
+            "SirenCheckList",
+            "SirenOverdue",
+        ] {
+            let (entity, _) = list_entity(name).unwrap();
+            assert!(ids_query(entity).is_some(), "{name}");
+        }
+        for name in ["CompanyTree", "CompanyPath", "SirenCheckHistory"] {
+            assert!(ids_query(item_entity(name)).is_some(), "{name}");
+        }
+        assert!(ids_query("Kind").is_none());
+    }
+}
@@ -0,0 +1,160 @@
+use chrono::NaiveDateTime;
+use hyper::{header, HeaderMap};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::ServiceError,
+    users::{load_user, UserData},
+};
+
+const PREFIX: &str = "rgo_";
+const KEY_LEN: usize = 40;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ApiKey {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    pub prefix: String,
+    pub role: i64,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked: bool,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ApiKeyParams {
+    pub user_id: i64,
+    pub name: String,
+    pub role: i64,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+/// A created key, the only time its value is shown.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NewApiKey {
+    pub id: i64,
+    pub key: String,
+}
+
+fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// Owner of the key from `Authorization: ApiKey <key>` as it is in the table
+/// now, with the lower of the roles of the key and the owner. Without the header `None`
+/// is returned, a bad key is an error.
+pub async fn api_key_user(
+    pool: &RpelPool,
+    headers: &HeaderMap,
+) -> Result<Option<UserData>, ServiceError> {
+    let Some(key) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("ApiKey "))
+    else {
+        return Ok(None);
+    };
+    let client = pool.get().await?;
+    let row = client
+        .query_opt(
+            "UPDATE api_keys SET last_used_at = now()
+            WHERE key_hash = $1
+            AND NOT revoked
+            AND (expires_at IS NULL OR expires_at > now())
+            RETURNING user_id, role",
+            &[&hash_key(key.trim())],
+        )
+        .await?
+        .ok_or(ServiceError::NotAuth)?;
+    let mut user = load_user(pool, row.get("user_id"))
+        .await?
+        .ok_or(ServiceError::NotAuth)?;
+    // Roles are thresholds, not bit sets.
+    user.role = user.role.min(row.get("role"));
+    Ok(Some(user))
+}
+
+impl ApiKey {
+    pub async fn get_by_user(pool: &RpelPool, user_id: i64) -> Result<Vec<ApiKey>, ServiceError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT
+                    id,
+                    user_id,
+                    name,
+                    prefix,
+                    role,
+                    expires_at,
+                    last_used_at,
+                    revoked,
+                    created_at
+                FROM
+                    api_keys
+                WHERE
+                    user_id = $1
+                ORDER BY
+                    created_at DESC",
+                &[&user_id],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| ApiKey {
+                id: row.get("id"),
+                user_id: row.get("user_id"),
+                name: row.get("name"),
+                prefix: row.get("prefix"),
+                role: row.get("role"),
+                expires_at: row.get("expires_at"),
+                last_used_at: row.get("last_used_at"),
+                revoked: row.get("revoked"),
+                created_at: row.get("created_at"),
+            })
+            .collect())
+    }
+
+    pub async fn insert(pool: &RpelPool, params: ApiKeyParams) -> Result<NewApiKey, ServiceError> {
+        let key: String = format!(
+            "{PREFIX}{}",
+            thread_rng()
+                .sample_iter(Alphanumeric)
+                .take(KEY_LEN)
+                .map(char::from)
+                .collect::<String>()
+        );
+        let prefix = &key[..PREFIX.len() + 4];
+        let client = pool.get().await?;
+        let row = client
+            .query_one(
+                "INSERT INTO api_keys (user_id, name, prefix, key_hash, role, expires_at)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                RETURNING id",
+                &[
+                    &params.user_id,
+                    &params.name,
+                    &prefix,
+                    &hash_key(&key),
+                    &params.role,
+                    &params.expires_at,
+                ],
+            )
+            .await?;
+        Ok(NewApiKey {
+            id: row.get("id"),
+            key,
+        })
+    }
+
+    pub async fn revoke(pool: &RpelPool, id: i64) -> Result<u64, ServiceError> {
+        let client = pool.get().await?;
+        Ok(client
+            .execute("UPDATE api_keys SET revoked = true WHERE id = $1", &[&id])
+            .await?)
+    }
+}
@@ -0,0 +1,327 @@
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use chrono::NaiveDateTime;
+use hyper::{Body, Response};
+use image::ImageOutputFormat;
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio_postgres::Row;
+
+use crate::{
+    config,
+    dbo::DbObject,
+    error::ServiceError,
+    messages::WsMsg,
+    services::{file_response, json_response},
+};
+
+const DEFAULT_DIR: &str = "attachments";
+const DEFAULT_MAX_SIZE: usize = 10 * 1024 * 1024;
+const DEFAULT_TYPES: &str = "application/pdf,image/jpeg,image/png,image/gif,image/webp";
+const DEFAULT_THUMB_SIZE: u32 = 200;
+
+const SELECT: &str = "
+    SELECT
+        id,
+        entity,
+        entity_id,
+        filename,
+        content_type,
+        size,
+        hash,
+        thumbnail,
+        user_id,
+        created_at
+    FROM
+        attachments";
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Attachment {
+    pub id: i64,
+    pub entity: String,
+    pub entity_id: i64,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    pub hash: String,
+    pub thumbnail: bool,
+    pub user_id: Option<i64>,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub enum AttachmentObject {
+    GetAttachments { entity: String, entity_id: i64 },
+    GetAttachment(i64),
+    GetThumbnail(i64),
+    DeleteAttachment(i64),
+}
+
+/// Uploaded file before it is stored.
+pub struct Upload {
+    pub entity: String,
+    pub entity_id: i64,
+    pub filename: String,
+    pub data: Vec<u8>,
+}
+
+pub fn max_size() -> usize {
+    config::var_or("RGO_ATTACHMENT_MAX_SIZE", DEFAULT_MAX_SIZE)
+}
+
+fn entity_table(entity: &str) -> Option<&'static str> {
+    match entity {
+        "Certificate" => Some("certificates"),
+        "Contact" => Some("contacts"),
+        "Practice" => Some("practices"),
+        _ => None,
+    }
+}
+
+pub fn storage_dir() -> PathBuf {
+    PathBuf::from(config::var_or(
+        "RGO_ATTACHMENT_DIR",
+        DEFAULT_DIR.to_string(),
+    ))
+}
+
+/// Files are stored once per content hash, fanned out by its first byte.
+fn file_path(hash: &str) -> PathBuf {
+    storage_dir().join(&hash[..2]).join(hash)
+}
+
+fn thumb_path(hash: &str) -> PathBuf {
+    storage_dir()
+        .join("thumbs")
+        .join(&hash[..2])
+        .join(format!("{hash}.png"))
+}
+
+/// Content type detected from the data itself, the client provided one is
+/// not trusted.
+fn sniff(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"%PDF-") {
+        return Some("application/pdf");
+    }
+    match image::guess_format(data).ok()? {
+        image::ImageFormat::Jpeg => Some("image/jpeg"),
+        image::ImageFormat::Png => Some("image/png"),
+        image::ImageFormat::Gif => Some("image/gif"),
+        image::ImageFormat::WebP => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Keeps the last path component and drops characters unsafe in headers.
+fn clean_filename(filename: &str) -> String {
+    let name: String = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .collect();
+    match name.trim() {
+        "" => String::from("file"),
+        name => name.to_string(),
+    }
+}
+
+fn thumbnail(data: &[u8]) -> Result<Vec<u8>, ServiceError> {
+    let size = config::var_or("RGO_THUMB_SIZE", DEFAULT_THUMB_SIZE);
+    let mut thumb = Cursor::new(Vec::new());
+    image::load_from_memory(data)?
+        .thumbnail(size, size)
+        .write_to(&mut thumb, ImageOutputFormat::Png)?;
+    Ok(thumb.into_inner())
+}
+
+pub async fn write_once(path: PathBuf, data: &[u8]) -> Result<(), ServiceError> {
+    if fs::try_exists(&path).await? {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    fs::write(path, data).await?;
+    Ok(())
+}
+
+impl From<&Row> for Attachment {
+    fn from(row: &Row) -> Self {
+        Attachment {
+            id: row.get("id"),
+            entity: row.get("entity"),
+            entity_id: row.get("entity_id"),
+            filename: row.get("filename"),
+            content_type: row.get("content_type"),
+            size: row.get("size"),
+            hash: row.get("hash"),
+            thumbnail: row.get("thumbnail"),
+            user_id: row.get("user_id"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+impl Attachment {
+    pub async fn get(pool: &RpelPool, id: i64) -> Result<Attachment, ServiceError> {
+        let client = pool.get().await?;
+        let row = client
+            .query_opt(format!("{SELECT} WHERE id = $1").as_str(), &[&id])
+            .await?
+            .ok_or_else(|| ServiceError::BadRequest(format!("no attachment {id}")))?;
+        Ok(Attachment::from(&row))
+    }
+
+    pub async fn get_by_entity(
+        pool: &RpelPool,
+        entity: &str,
+        entity_id: i64,
+    ) -> Result<Vec<Attachment>, ServiceError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                format!("{SELECT} WHERE entity = $1 AND entity_id = $2 ORDER BY created_at, id")
+                    .as_str(),
+                &[&entity, &entity_id],
+            )
+            .await?;
+        Ok(rows.iter().map(Attachment::from).collect())
+    }
+
+    /// Checks size, type and the linked entity, writes the file and its
+    /// thumbnail to the storage and records metadata.
+    pub async fn store(
+        pool: &RpelPool,
+        upload: Upload,
+        user_id: i64,
+    ) -> Result<Attachment, ServiceError> {
+        let table = entity_table(&upload.entity).ok_or_else(|| {
+            ServiceError::BadRequest(format!("attachments not allowed for {}", upload.entity))
+        })?;
+        if upload.data.is_empty() || upload.data.len() > max_size() {
+            return Err(ServiceError::BadRequest(format!(
+                "bad attachment size: {}",
+                upload.data.len()
+            )));
+        }
+        let allowed = config::var_or("RGO_ATTACHMENT_TYPES", DEFAULT_TYPES.to_string());
+        let content_type = sniff(&upload.data)
+            .filter(|content_type| allowed.split(',').any(|t| t.trim() == *content_type))
+            .ok_or_else(|| ServiceError::BadRequest("attachment type not allowed".to_string()))?;
+        let client = pool.get().await?;
+        client
+            .query_opt(
+                format!("SELECT id FROM {table} WHERE id = $1").as_str(),
+                &[&upload.entity_id],
+            )
+            .await?
+            .ok_or_else(|| {
+                ServiceError::BadRequest(format!("no {} {}", upload.entity, upload.entity_id))
+            })?;
+
+        let hash = format!("{:x}", Sha256::digest(&upload.data));
+        write_once(file_path(&hash), &upload.data).await?;
+        let is_image = content_type.starts_with("image/");
+        if is_image && !fs::try_exists(thumb_path(&hash)).await? {
+            let data = upload.data.clone();
+            let thumb = tokio::task::spawn_blocking(move || thumbnail(&data))
+                .await
+                .map_err(|err| ServiceError::Std(Box::new(err)))??;
+            write_once(thumb_path(&hash), &thumb).await?;
+        }
+
+        let row = client
+            .query_one(
+                "INSERT INTO attachments
+                    (entity, entity_id, filename, content_type, size, hash, thumbnail, user_id)
+                VALUES
+                    ($1, $2, $3, $4, $5, $6, $7, $8)
+                RETURNING id, created_at",
+                &[
+                    &upload.entity,
+                    &upload.entity_id,
+                    &clean_filename(&upload.filename),
+                    &content_type,
+                    &(upload.data.len() as i64),
+                    &hash,
+                    &is_image,
+                    &user_id,
+                ],
+            )
+            .await?;
+        Attachment::get(pool, row.get("id")).await
+    }
+
+    /// Removes metadata, the file itself only when no other attachment
+    /// shares its content.
+    pub async fn delete(pool: &RpelPool, id: i64) -> Result<u64, ServiceError> {
+        let attachment = Attachment::get(pool, id).await?;
+        let client = pool.get().await?;
+        let deleted = client
+            .execute("DELETE FROM attachments WHERE id = $1", &[&id])
+            .await?;
+        let shared = client
+            .query_one(
+                "SELECT COUNT(*) AS count FROM attachments WHERE hash = $1",
+                &[&attachment.hash],
+            )
+            .await?
+            .get::<_, i64>("count");
+        if shared == 0 {
+            for path in [file_path(&attachment.hash), thumb_path(&attachment.hash)] {
+                if fs::try_exists(&path).await? {
+                    fs::remove_file(path).await?;
+                }
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+pub async fn attachment_cmd(
+    obj: AttachmentObject,
+    pool: &RpelPool,
+) -> Result<Response<Body>, ServiceError> {
+    let msg = match obj {
+        AttachmentObject::GetAttachments { entity, entity_id } => WsMsg::from_dbo(
+            "GetAttachments",
+            String::from("AttachmentList"),
+            Attachment::get_by_entity(pool, &entity, entity_id)
+                .await
+                .map(DbObject::AttachmentList),
+        ),
+        AttachmentObject::GetAttachment(id) => {
+            let attachment = Attachment::get(pool, id).await?;
+            return file_response(
+                &attachment.content_type,
+                &attachment.filename,
+                fs::read(file_path(&attachment.hash)).await?,
+            );
+        }
+        AttachmentObject::GetThumbnail(id) => {
+            let attachment = Attachment::get(pool, id).await?;
+            if !attachment.thumbnail {
+                return Err(ServiceError::BadRequest(format!(
+                    "attachment {id} has no thumbnail"
+                )));
+            }
+            return file_response(
+                "image/png",
+                &format!("thumb_{id}.png"),
+                fs::read(thumb_path(&attachment.hash)).await?,
+            );
+        }
+        AttachmentObject::DeleteAttachment(id) => WsMsg::from_dbo(
+            "DeleteAttachment",
+            String::from("Attachment"),
+            Attachment::delete(pool, id).await.map(|_| DbObject::Null),
+        ),
+    };
+    json_response(json!(msg))
+}
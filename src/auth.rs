@@ -1,8 +1,12 @@
+use async_trait::async_trait;
+use log::{error, info};
+use rpel::RpelPool;
 use serde::{Deserialize, Serialize};
 
 use crate::error::ServiceError;
+use crate::ldap::LdapBackend;
 use crate::messages::{ClientMessage, Command};
-use crate::users::Users;
+use crate::users::{table_user, Impersonator, UserData, UserObject, Users};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Auth {
@@ -10,20 +14,137 @@ pub struct Auth {
     pub p: String,
 }
 
+/// Access token `t` with the role `r` and the refresh token `f`.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct A {
     pub t: String,
     pub r: i64,
+    #[serde(default)]
+    pub f: String,
 }
 
+/// Body of `/go/refresh` and `/go/logout`.
+#[derive(Debug, Deserialize)]
+pub struct Refresh {
+    pub f: String,
+}
+
+/// Reply of `/go/check`, `imp` is the admin of an impersonation token.
 #[derive(Serialize)]
 pub struct C {
     pub r: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub imp: Option<Impersonator>,
+}
+
+/// Checks the command against the user of an API key, when the request had
+/// one, otherwise against the user of the `addon` token.
+pub fn check(
+    users: &Users,
+    message: ClientMessage,
+    api_user: Option<UserData>,
+) -> Result<(UserData, Command), ServiceError> {
+    let user = match api_user {
+        // A key acts for its owner, it does not manage the account.
+        Some(_)
+            if matches!(
+                message.command,
+                Command::User(
+                    UserObject::Me
+                        | UserObject::ChangeMyPassword { .. }
+                        | UserObject::UpdateMyProfile(_)
+                )
+            ) =>
+        {
+            return Err(ServiceError::NotPermission);
+        }
+        Some(user) => user,
+        None => users
+            .get_user(&message.addon)
+            .ok_or(ServiceError::NotAuth)?,
+    };
+    let command = user.permissions(message.command)?;
+    Ok((user, command))
+}
+
+/// Logs what an impersonating admin does.
+pub fn audit(user: &UserData, action: impl FnOnce() -> String) {
+    if let Some(admin) = &user.impersonator {
+        info!(
+            "user {} ({}) as {} ({}): {}",
+            admin.name,
+            admin.id,
+            user.name,
+            user.id,
+            action()
+        );
+    }
+}
+
+/// A way to check a username and password for `/go/login`.
+#[async_trait]
+pub trait AuthBackend: Send + Sync {
+    /// The authenticated user, `None` for a wrong name or password.
+    async fn login(
+        &self,
+        pool: &RpelPool,
+        name: &str,
+        key: &str,
+    ) -> Result<Option<UserData>, ServiceError>;
+}
+
+/// Names and keys of the `users` table.
+pub struct TableBackend;
+
+#[async_trait]
+impl AuthBackend for TableBackend {
+    async fn login(
+        &self,
+        pool: &RpelPool,
+        name: &str,
+        key: &str,
+    ) -> Result<Option<UserData>, ServiceError> {
+        if key.is_empty() {
+            return Ok(None);
+        }
+        table_user(pool, name, key).await
+    }
+}
+
+/// Backends from `RGO_AUTH_BACKENDS`, a comma separated list of `table`
+/// and `ldap` tried in order.
+pub fn backends() -> Vec<Box<dyn AuthBackend>> {
+    dotenv::var("RGO_AUTH_BACKENDS")
+        .unwrap_or_else(|_| String::from("table"))
+        .split(',')
+        .filter_map(|name| -> Option<Box<dyn AuthBackend>> {
+            match name.trim() {
+                "table" => Some(Box::new(TableBackend)),
+                "ldap" => Some(Box::new(LdapBackend::from_env())),
+                "" => None,
+                name => {
+                    error!("unknown auth backend {name:?}");
+                    None
+                }
+            }
+        })
+        .collect()
 }
 
-pub fn check(users: &Users, message: ClientMessage) -> Result<Command, ServiceError> {
-    let user = users
-        .get_user(&message.addon)
-        .ok_or(ServiceError::NotAuth)?;
-    user.permissions(message.command)
+/// First successful login of the backends. A failing backend, like an
+/// unreachable directory, is logged and the next one is tried.
+pub async fn authenticate(
+    backends: &[Box<dyn AuthBackend>],
+    pool: &RpelPool,
+    name: &str,
+    key: &str,
+) -> Option<UserData> {
+    for backend in backends {
+        match backend.login(pool, name, key).await {
+            Ok(Some(user)) => return Some(user),
+            Ok(None) => {}
+            Err(err) => error!("auth backend: {err}"),
+        }
+    }
+    None
 }
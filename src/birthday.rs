@@ -0,0 +1,136 @@
+use chrono::{Datelike, Local, NaiveDate};
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+
+use crate::{config, error::ServiceError};
+
+const DEFAULT_BIRTHDAY_DAYS: i64 = 14;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ContactBirthday {
+    pub id: i64,
+    pub name: Option<String>,
+    pub company_id: Option<i64>,
+    pub company_name: Option<String>,
+    pub birthday: NaiveDate,
+    pub next_date: NaiveDate,
+    pub days_left: i64,
+    pub age: i32,
+    pub jubilee: bool,
+}
+
+/// Birthday in the given year, Feb 29 is celebrated on Feb 28 in common years.
+fn anniversary(birthday: NaiveDate, year: i32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, birthday.month(), birthday.day())
+        .or_else(|| NaiveDate::from_ymd_opt(year, 2, 28))
+        .unwrap_or(birthday)
+}
+
+pub fn next_birthday(birthday: NaiveDate, today: NaiveDate) -> NaiveDate {
+    let date = anniversary(birthday, today.year());
+    if date >= today {
+        date
+    } else {
+        anniversary(birthday, today.year() + 1)
+    }
+}
+
+impl ContactBirthday {
+    /// Contacts with a birthday within `RGO_BIRTHDAY_DAYS` from today,
+    /// nearest first.
+    pub async fn get_near(pool: &RpelPool) -> Result<Vec<ContactBirthday>, ServiceError> {
+        let days = config::var_or("RGO_BIRTHDAY_DAYS", DEFAULT_BIRTHDAY_DAYS);
+        let today = Local::now().date_naive();
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT
+                    c.id,
+                    c.name,
+                    c.company_id,
+                    co.name AS company_name,
+                    c.birthday
+                FROM
+                    contacts AS c
+                LEFT JOIN
+                    companies AS co ON co.id = c.company_id
+                WHERE
+                    c.birthday IS NOT NULL",
+                &[],
+            )
+            .await?;
+        let mut birthdays: Vec<ContactBirthday> = rows
+            .iter()
+            .filter_map(|row| {
+                let birthday: NaiveDate = row.get("birthday");
+                let next_date = next_birthday(birthday, today);
+                let days_left = (next_date - today).num_days();
+                let age = next_date.year() - birthday.year();
+                (days_left <= days).then(|| ContactBirthday {
+                    id: row.get("id"),
+                    name: row.get("name"),
+                    company_id: row.get("company_id"),
+                    company_name: row.get("company_name"),
+                    birthday,
+                    next_date,
+                    days_left,
+                    age,
+                    jubilee: age > 0 && age % 5 == 0,
+                })
+            })
+            .collect();
+        birthdays.sort_by(|a, b| a.days_left.cmp(&b.days_left).then(a.name.cmp(&b.name)));
+        Ok(birthdays)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn leap_day_birthday_in_leap_year() {
+        assert_eq!(anniversary(date(2000, 2, 29), 2024), date(2024, 2, 29));
+        assert_eq!(
+            next_birthday(date(2000, 2, 29), date(2024, 2, 1)),
+            date(2024, 2, 29)
+        );
+    }
+
+    #[test]
+    fn leap_day_birthday_in_common_year() {
+        assert_eq!(anniversary(date(2000, 2, 29), 2023), date(2023, 2, 28));
+        assert_eq!(
+            next_birthday(date(2000, 2, 29), date(2023, 2, 28)),
+            date(2023, 2, 28)
+        );
+        assert_eq!(
+            next_birthday(date(2000, 2, 29), date(2023, 3, 1)),
+            date(2024, 2, 29)
+        );
+    }
+
+    #[test]
+    fn birthday_today_is_not_moved() {
+        assert_eq!(
+            next_birthday(date(1990, 6, 15), date(2024, 6, 15)),
+            date(2024, 6, 15)
+        );
+    }
+
+    #[test]
+    fn passed_birthday_rolls_over_to_next_year() {
+        assert_eq!(
+            next_birthday(date(1990, 1, 5), date(2024, 12, 20)),
+            date(2025, 1, 5)
+        );
+        assert_eq!(
+            next_birthday(date(1990, 12, 31), date(2024, 12, 31)),
+            date(2024, 12, 31)
+        );
+    }
+}
@@ -0,0 +1,20 @@
+use std::str::FromStr;
+
+pub fn var_or<T: FromStr>(key: &str, default: T) -> T {
+    dotenv::var(key)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+/// Parses `key:value` pairs separated by commas, skipping malformed entries.
+pub fn var_pairs<K: FromStr, V: FromStr>(key: &str) -> Vec<(K, V)> {
+    dotenv::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|pair| {
+            let (k, v) = pair.split_once(':')?;
+            Some((k.trim().parse().ok()?, v.trim().parse().ok()?))
+        })
+        .collect()
+}
@@ -0,0 +1,194 @@
+use hyper::{
+    header::{self, HeaderValue},
+    HeaderMap, Method,
+};
+
+use log::warn;
+
+use crate::config;
+
+const DEFAULT_METHODS: &str = "GET, POST, OPTIONS";
+const CREDENTIALS_HEADERS: &str = "Authorization, Content-Type";
+const DEFAULT_MAX_AGE: u64 = 600;
+
+/// Cross-origin rules from `RGO_CORS_ORIGINS` (comma separated, `*` for
+/// any), `RGO_CORS_METHODS`, `RGO_CORS_HEADERS`, `RGO_CORS_CREDENTIALS` and
+/// `RGO_CORS_MAX_AGE`. A listed origin is sent back as it came, others get
+/// no `Access-Control-Allow-Origin` at all. Browsers take no wildcards with
+/// credentials, so then `*` allows no origin and `*` headers are
+/// `Authorization, Content-Type`.
+pub struct CorsPolicy {
+    origins: Vec<String>,
+    methods: String,
+    headers: String,
+    credentials: bool,
+    max_age: u64,
+}
+
+fn list(key: &str, default: &str) -> Vec<String> {
+    config::var_or(key, default.to_string())
+        .split(',')
+        .map(|value| value.trim().trim_end_matches('/').to_string())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+fn insert(headers: &mut HeaderMap, name: header::HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+impl CorsPolicy {
+    pub fn from_env() -> Self {
+        CorsPolicy {
+            origins: list("RGO_CORS_ORIGINS", "*"),
+            methods: list("RGO_CORS_METHODS", DEFAULT_METHODS).join(", "),
+            headers: list("RGO_CORS_HEADERS", "*").join(", "),
+            credentials: config::var_or("RGO_CORS_CREDENTIALS", false),
+            max_age: config::var_or("RGO_CORS_MAX_AGE", DEFAULT_MAX_AGE),
+        }
+        .checked()
+    }
+
+    /// Drops the wildcards a policy with credentials can not use.
+    fn checked(mut self) -> Self {
+        if !self.credentials {
+            return self;
+        }
+        if self.any_origin() {
+            warn!("RGO_CORS_ORIGINS * is ignored with RGO_CORS_CREDENTIALS, list the origins");
+            self.origins.retain(|origin| origin != "*");
+        }
+        if self.headers == "*" {
+            self.headers = CREDENTIALS_HEADERS.to_string();
+        }
+        self
+    }
+
+    fn any_origin(&self) -> bool {
+        self.origins.iter().any(|origin| origin == "*")
+    }
+
+    /// Value of `Access-Control-Allow-Origin` for the request origin.
+    fn allowed_origin<'a>(&self, origin: Option<&'a str>) -> Option<&'a str> {
+        if self.any_origin() {
+            return Some("*");
+        }
+        let origin = origin?;
+        let trimmed = origin.trim_end_matches('/');
+        self.origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(trimmed))
+            .then_some(origin)
+    }
+
+    /// Adds the CORS headers for a request with the `request` headers and
+    /// `method`, a preflight gets the allowed methods, headers and max-age.
+    pub fn apply(&self, headers: &mut HeaderMap, request: &HeaderMap, method: &Method) {
+        let origin = request
+            .get(header::ORIGIN)
+            .and_then(|value| value.to_str().ok());
+        if !self.any_origin() {
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        let Some(allowed) = self.allowed_origin(origin) else {
+            return;
+        };
+        insert(headers, header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+        if self.credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        if method != Method::OPTIONS {
+            return;
+        }
+        insert(headers, header::ACCESS_CONTROL_ALLOW_METHODS, &self.methods);
+        insert(headers, header::ACCESS_CONTROL_ALLOW_HEADERS, &self.headers);
+        insert(
+            headers,
+            header::ACCESS_CONTROL_MAX_AGE,
+            &self.max_age.to_string(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &[&str], credentials: bool) -> CorsPolicy {
+        CorsPolicy {
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            methods: DEFAULT_METHODS.to_string(),
+            headers: "*".to_string(),
+            credentials,
+            max_age: DEFAULT_MAX_AGE,
+        }
+        .checked()
+    }
+
+    fn preflight(policy: &CorsPolicy, origin: &str) -> HeaderMap {
+        let mut request = HeaderMap::new();
+        request.insert(header::ORIGIN, HeaderValue::from_str(origin).unwrap());
+        request.insert(
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            HeaderValue::from_static("x-anything"),
+        );
+        let mut headers = HeaderMap::new();
+        policy.apply(&mut headers, &request, &Method::OPTIONS);
+        headers
+    }
+
+    #[test]
+    fn listed_origins_are_reflected() {
+        let policy = policy(&["https://app.example.org"], false);
+        assert_eq!(
+            policy.allowed_origin(Some("https://APP.example.org/")),
+            Some("https://APP.example.org/")
+        );
+        assert_eq!(policy.allowed_origin(Some("https://evil.example")), None);
+        assert_eq!(
+            policy.allowed_origin(Some("https://app.example.org.evil.example")),
+            None
+        );
+        assert_eq!(policy.allowed_origin(None), None);
+    }
+
+    #[test]
+    fn wildcard_without_credentials_allows_any() {
+        let policy = policy(&["*"], false);
+        assert_eq!(
+            policy.allowed_origin(Some("https://any.example")),
+            Some("*")
+        );
+        assert_eq!(policy.allowed_origin(None), Some("*"));
+    }
+
+    #[test]
+    fn wildcard_with_credentials_reflects_listed_only() {
+        let policy = policy(&["*", "https://app.example.org"], true);
+        assert_eq!(policy.allowed_origin(Some("https://evil.example")), None);
+        assert_eq!(
+            policy.allowed_origin(Some("https://app.example.org")),
+            Some("https://app.example.org")
+        );
+        assert!(preflight(&policy, "https://evil.example")
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+
+    #[test]
+    fn preflight_with_credentials_does_not_echo_asked_headers() {
+        let policy = policy(&["https://app.example.org"], true);
+        let headers = preflight(&policy, "https://app.example.org");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            CREDENTIALS_HEADERS
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[header::VARY], "Origin");
+    }
+}
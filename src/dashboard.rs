@@ -0,0 +1,143 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_value, Value};
+
+use crate::{config, error::ServiceError};
+
+const DEFAULT_EDUCATION_YEARS: i32 = 5;
+const DEFAULT_RECENT_LIMIT: i64 = 10;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CountItem {
+    pub id: Option<i64>,
+    pub name: Option<String>,
+    pub count: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DashboardEvent {
+    pub id: i64,
+    pub date: NaiveDate,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecentChange {
+    pub entity: String,
+    pub id: i64,
+    pub name: Option<String>,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Dashboard {
+    pub contacts: i64,
+    pub companies: i64,
+    pub sirens: i64,
+    pub sirens_by_type: Vec<CountItem>,
+    pub sirens_by_status: Vec<CountItem>,
+    pub practices: Vec<DashboardEvent>,
+    pub educations: Vec<DashboardEvent>,
+    pub overdue_educations: i64,
+    pub recent: Vec<RecentChange>,
+}
+
+impl Dashboard {
+    /// Collects all counters in a single query. An education is overdue when
+    /// the last one of a contact ended more than `RGO_EDUCATION_YEARS` ago.
+    pub async fn get(pool: &RpelPool) -> Result<Dashboard, ServiceError> {
+        let education_years = config::var_or("RGO_EDUCATION_YEARS", DEFAULT_EDUCATION_YEARS);
+        let recent_limit = config::var_or("RGO_DASHBOARD_RECENT", DEFAULT_RECENT_LIMIT);
+        let client = pool.get().await?;
+        let row = client
+            .query_one(
+                "SELECT
+                    (SELECT COUNT(*) FROM contacts) AS contacts,
+                    (SELECT COUNT(*) FROM companies) AS companies,
+                    (SELECT COUNT(*) FROM sirens) AS sirens,
+                    (
+                        SELECT COALESCE(json_agg(t ORDER BY t.count DESC), '[]')
+                        FROM (
+                            SELECT st.id, st.name, COUNT(s.id) AS count
+                            FROM sirens AS s
+                            LEFT JOIN siren_types AS st ON st.id = s.siren_type_id
+                            GROUP BY st.id, st.name
+                        ) AS t
+                    ) AS sirens_by_type,
+                    (
+                        SELECT COALESCE(json_agg(t ORDER BY t.id), '[]')
+                        FROM (
+                            SELECT s.stage::bigint AS id, NULL::text AS name, COUNT(*) AS count
+                            FROM sirens AS s
+                            GROUP BY s.stage
+                        ) AS t
+                    ) AS sirens_by_status,
+                    (
+                        SELECT COALESCE(json_agg(t ORDER BY t.date), '[]')
+                        FROM (
+                            SELECT p.id, p.date_of_practice AS date, c.name
+                            FROM practices AS p
+                            LEFT JOIN companies AS c ON c.id = p.company_id
+                            WHERE p.date_of_practice >= CURRENT_DATE
+                            AND p.date_of_practice < date_trunc('month', CURRENT_DATE) + INTERVAL '1 month'
+                        ) AS t
+                    ) AS practices,
+                    (
+                        SELECT COALESCE(json_agg(t ORDER BY t.date), '[]')
+                        FROM (
+                            SELECT e.id, e.start_date AS date, c.name
+                            FROM educations AS e
+                            LEFT JOIN contacts AS c ON c.id = e.contact_id
+                            WHERE e.start_date >= CURRENT_DATE
+                            AND e.start_date < date_trunc('month', CURRENT_DATE) + INTERVAL '1 month'
+                        ) AS t
+                    ) AS educations,
+                    (
+                        SELECT COUNT(*)
+                        FROM (
+                            SELECT contact_id
+                            FROM educations
+                            WHERE contact_id IS NOT NULL
+                            GROUP BY contact_id
+                            HAVING MAX(end_date) < CURRENT_DATE - make_interval(years => $1)
+                        ) AS t
+                    ) AS overdue_educations,
+                    (
+                        SELECT COALESCE(json_agg(t ORDER BY t.updated_at DESC), '[]')
+                        FROM (
+                            SELECT 'Contact' AS entity, id, name, updated_at FROM contacts
+                            WHERE updated_at IS NOT NULL
+                            UNION ALL
+                            SELECT 'Company', id, name, updated_at FROM companies
+                            WHERE updated_at IS NOT NULL
+                            UNION ALL
+                            SELECT 'Siren', id, address, updated_at FROM sirens
+                            WHERE updated_at IS NOT NULL
+                            UNION ALL
+                            SELECT 'Practice', id, topic, updated_at FROM practices
+                            WHERE updated_at IS NOT NULL
+                            UNION ALL
+                            SELECT 'Education', e.id, c.name, e.updated_at FROM educations AS e
+                            LEFT JOIN contacts AS c ON c.id = e.contact_id
+                            WHERE e.updated_at IS NOT NULL
+                            ORDER BY updated_at DESC
+                            LIMIT $2
+                        ) AS t
+                    ) AS recent",
+                &[&education_years, &recent_limit],
+            )
+            .await?;
+        Ok(Dashboard {
+            contacts: row.get("contacts"),
+            companies: row.get("companies"),
+            sirens: row.get("sirens"),
+            sirens_by_type: from_value(row.get::<_, Value>("sirens_by_type"))?,
+            sirens_by_status: from_value(row.get::<_, Value>("sirens_by_status"))?,
+            practices: from_value(row.get::<_, Value>("practices"))?,
+            educations: from_value(row.get::<_, Value>("educations"))?,
+            overdue_educations: row.get("overdue_educations"),
+            recent: from_value(row.get::<_, Value>("recent"))?,
+        })
+    }
+}
@@ -17,20 +17,38 @@ use rpel::{
 };
 use serde::{Deserialize, Serialize};
 
+use crate::attachment::Attachment;
+use crate::birthday::ContactBirthday;
+use crate::dashboard::Dashboard;
+use crate::duplicate::ContactDuplicate;
 use crate::error::ServiceError;
+use crate::hierarchy::{Hierarchy, ParentLink, PathItem, TreeNode};
+use crate::history::{self, EntityVersion};
 use crate::messages::Item;
+use crate::siren_check::{SirenCheck, SirenCheckList, SirenOverdue};
+use crate::validate::{normalized_item, validate};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum DbObject {
     Null,
+    Attachment(Box<Attachment>),
+    AttachmentList(Vec<Attachment>),
     Certificate(Certificate),
     CertificateList(Vec<CertificateList>),
     Company(Box<Company>),
     CompanyList(Vec<CompanyList>),
+    CompanyParent(ParentLink),
+    CompanyTree(Vec<TreeNode>),
     Contact(Box<Contact>),
     ContactList(Vec<ContactList>),
+    ContactBirthday(Vec<ContactBirthday>),
+    ContactDuplicate(Vec<ContactDuplicate>),
+    Dashboard(Box<Dashboard>),
     Department(Department),
     DepartmentList(Vec<DepartmentList>),
+    DepartmentParent(ParentLink),
+    DepartmentTree(Vec<TreeNode>),
+    EntityVersionList(Vec<EntityVersion>),
     Education(Education),
     EducationList(Vec<EducationList>),
     EducationShort(Vec<EducationShort>),
@@ -46,8 +64,12 @@ pub enum DbObject {
     Scope(Scope),
     ScopeList(Vec<ScopeList>),
     SelectItem(Vec<SelectItem>),
+    TreePath(Vec<PathItem>),
     Siren(Box<Siren>),
     SirenList(Vec<SirenList>),
+    SirenCheck(SirenCheck),
+    SirenCheckList(Vec<SirenCheckList>),
+    SirenOverdue(Vec<SirenOverdue>),
     SirenType(SirenType),
     SirenTypeList(Vec<SirenTypeList>),
     User(User),
@@ -58,14 +80,24 @@ impl DbObject {
     pub fn name(&self) -> String {
         match self {
             DbObject::Null => String::new(),
+            DbObject::Attachment(_) => String::from("Attachment"),
+            DbObject::AttachmentList(_) => String::from("AttachmentList"),
             DbObject::Certificate(_) => String::from("Certificate"),
             DbObject::CertificateList(_) => String::from("CertificateList"),
             DbObject::Company(_) => String::from("Company"),
             DbObject::CompanyList(_) => String::from("CompanyList"),
+            DbObject::CompanyParent(_) => String::from("CompanyParent"),
+            DbObject::CompanyTree(_) => String::from("CompanyTree"),
             DbObject::Contact(_) => String::from("Contact"),
             DbObject::ContactList(_) => String::from("ContactList"),
+            DbObject::ContactBirthday(_) => String::from("ContactBirthday"),
+            DbObject::ContactDuplicate(_) => String::from("ContactDuplicate"),
+            DbObject::Dashboard(_) => String::from("Dashboard"),
             DbObject::Department(_) => String::from("Department"),
             DbObject::DepartmentList(_) => String::from("DepartmentList"),
+            DbObject::DepartmentParent(_) => String::from("DepartmentParent"),
+            DbObject::DepartmentTree(_) => String::from("DepartmentTree"),
+            DbObject::EntityVersionList(_) => String::from("EntityVersionList"),
             DbObject::Education(_) => String::from("Education"),
             DbObject::EducationList(_) => String::from("EducationList"),
             DbObject::EducationShort(_) => String::from("EducationShort"),
@@ -81,8 +113,12 @@ impl DbObject {
             DbObject::Scope(_) => String::from("Scope"),
             DbObject::ScopeList(_) => String::from("ScopeList"),
             DbObject::SelectItem(_) => String::from("SelectItem"),
+            DbObject::TreePath(_) => String::from("TreePath"),
             DbObject::Siren(_) => String::from("Siren"),
             DbObject::SirenList(_) => String::from("SirenList"),
+            DbObject::SirenCheck(_) => String::from("SirenCheck"),
+            DbObject::SirenCheckList(_) => String::from("SirenCheckList"),
+            DbObject::SirenOverdue(_) => String::from("SirenOverdue"),
             DbObject::SirenType(_) => String::from("SirenType"),
             DbObject::SirenTypeList(_) => String::from("SirenTypeList"),
             DbObject::User(_) => String::from("User"),
@@ -95,8 +131,20 @@ pub async fn get_item(item: &Item, pool: &RpelPool) -> Result<DbObject, ServiceE
     match (item.name.as_str(), item.id) {
         ("Certificate", id) => Ok(DbObject::Certificate(Certificate::get(pool, id).await?)),
         ("Company", id) => Ok(DbObject::Company(Box::new(Company::get(pool, id).await?))),
+        ("CompanyPath", id) => Ok(DbObject::TreePath(
+            Hierarchy::Company.get_path(pool, id).await?,
+        )),
+        ("CompanyTree", id) => Ok(DbObject::CompanyTree(
+            Hierarchy::Company.get_subtree(pool, id).await?,
+        )),
         ("Contact", id) => Ok(DbObject::Contact(Box::new(Contact::get(pool, id).await?))),
         ("Department", id) => Ok(DbObject::Department(Department::get(pool, id).await?)),
+        ("DepartmentPath", id) => Ok(DbObject::TreePath(
+            Hierarchy::Department.get_path(pool, id).await?,
+        )),
+        ("DepartmentTree", id) => Ok(DbObject::DepartmentTree(
+            Hierarchy::Department.get_subtree(pool, id).await?,
+        )),
         ("Education", id) => Ok(DbObject::Education(Education::get(pool, id).await?)),
         ("Kind", id) => Ok(DbObject::Kind(Kind::get(pool, id).await?)),
         ("Post", id) => Ok(DbObject::Post(Post::get(pool, id).await?)),
@@ -104,6 +152,10 @@ pub async fn get_item(item: &Item, pool: &RpelPool) -> Result<DbObject, ServiceE
         ("Rank", id) => Ok(DbObject::Rank(Rank::get(pool, id).await?)),
         ("Scope", id) => Ok(DbObject::Scope(Scope::get(pool, id).await?)),
         ("Siren", id) => Ok(DbObject::Siren(Box::new(Siren::get(pool, id).await?))),
+        ("SirenCheck", id) => Ok(DbObject::SirenCheck(SirenCheck::get(pool, id).await?)),
+        ("SirenCheckHistory", id) => Ok(DbObject::SirenCheckList(
+            SirenCheckList::get_by_siren(pool, id).await?,
+        )),
         ("SirenType", id) => Ok(DbObject::SirenType(SirenType::get(pool, id).await?)),
         ("User", id) => Ok(DbObject::User(User::get(pool, id).await?)),
         (e, id) => Err(ServiceError::BadRequest(format!(
@@ -114,16 +166,25 @@ pub async fn get_item(item: &Item, pool: &RpelPool) -> Result<DbObject, ServiceE
 
 pub async fn get_list(name: &str, pool: &RpelPool) -> Result<DbObject, ServiceError> {
     match name {
+        "BirthdayNear" => Ok(DbObject::ContactBirthday(
+            ContactBirthday::get_near(pool).await?,
+        )),
         "CertificateList" => Ok(DbObject::CertificateList(
             CertificateList::get_all(pool).await?,
         )),
         "CompanyList" => Ok(DbObject::CompanyList(CompanyList::get_all(pool).await?)),
+        "CompanyTree" => Ok(DbObject::CompanyTree(
+            Hierarchy::Company.get_forest(pool).await?,
+        )),
         "CompanySelect" => Ok(DbObject::SelectItem(SelectItem::company_all(pool).await?)),
         "ContactList" => Ok(DbObject::ContactList(ContactList::get_all(pool).await?)),
         "ContactSelect" => Ok(DbObject::SelectItem(SelectItem::contact_all(pool).await?)),
         "DepartmentList" => Ok(DbObject::DepartmentList(
             DepartmentList::get_all(pool).await?,
         )),
+        "DepartmentTree" => Ok(DbObject::DepartmentTree(
+            Hierarchy::Department.get_forest(pool).await?,
+        )),
         "DepartmentSelect" => Ok(DbObject::SelectItem(
             SelectItem::department_all(pool).await?,
         )),
@@ -152,6 +213,10 @@ pub async fn get_list(name: &str, pool: &RpelPool) -> Result<DbObject, ServiceEr
         "ScopeSelect" => Ok(DbObject::SelectItem(SelectItem::scope_all(pool).await?)),
         // "SelectItem" =>
         "SirenList" => Ok(DbObject::SirenList(SirenList::get_all(pool).await?)),
+        "SirenCheckList" => Ok(DbObject::SirenCheckList(
+            SirenCheckList::get_all(pool).await?,
+        )),
+        "SirenOverdue" => Ok(DbObject::SirenOverdue(SirenOverdue::get_all(pool).await?)),
         "SirenTypeList" => Ok(DbObject::SirenTypeList(SirenTypeList::get_all(pool).await?)),
         "SirenTypeSelect" => Ok(DbObject::SelectItem(
             SelectItem::siren_type_all(pool).await?,
@@ -162,7 +227,7 @@ pub async fn get_list(name: &str, pool: &RpelPool) -> Result<DbObject, ServiceEr
 }
 
 pub async fn insert_item(object: DbObject, pool: &RpelPool) -> Result<i64, ServiceError> {
-    match object {
+    match validate(object, false, None)? {
         DbObject::Certificate(item) => Ok(Certificate::insert(pool, item).await?.id),
         DbObject::Company(item) => Ok(Company::insert(pool, *item).await?.id),
         DbObject::Contact(item) => Ok(Contact::insert(pool, *item).await?.id),
@@ -174,6 +239,7 @@ pub async fn insert_item(object: DbObject, pool: &RpelPool) -> Result<i64, Servi
         DbObject::Rank(item) => Ok(Rank::insert(pool, item).await?.id),
         DbObject::Scope(item) => Ok(Scope::insert(pool, item).await?.id),
         DbObject::Siren(item) => Ok(Siren::insert(pool, *item).await?.id),
+        DbObject::SirenCheck(item) => Ok(SirenCheck::insert(pool, item).await?.id),
         DbObject::SirenType(item) => Ok(SirenType::insert(pool, item).await?.id),
         DbObject::User(item) => Ok(User::insert(pool, item).await?.id),
         _ => Err(ServiceError::BadRequest("bad item object".to_string())),
@@ -181,11 +247,24 @@ pub async fn insert_item(object: DbObject, pool: &RpelPool) -> Result<i64, Servi
 }
 
 pub async fn update_item(object: DbObject, pool: &RpelPool) -> Result<i64, ServiceError> {
+    let stored = match normalized_item(&object)? {
+        Some(item) => Some(get_item(&item, pool).await?),
+        None => None,
+    };
+    let object = validate(object, true, stored.as_ref())?;
+    let versioned = history::versioned_item(&object)?;
+    if let Some(item) = &versioned {
+        history::before_update(pool, item).await?;
+    }
     let res = match object {
         DbObject::Certificate(item) => Certificate::update(pool, item).await,
         DbObject::Company(item) => Company::update(pool, *item).await,
+        DbObject::CompanyParent(link) => Ok(Hierarchy::Company.set_parent(pool, link).await?),
         DbObject::Contact(item) => Contact::update(pool, *item).await,
         DbObject::Department(item) => Department::update(pool, item).await,
+        DbObject::DepartmentParent(link) => {
+            Ok(Hierarchy::Department.set_parent(pool, link).await?)
+        }
         DbObject::Education(item) => Education::update(pool, item).await,
         DbObject::Kind(item) => Kind::update(pool, item).await,
         DbObject::Post(item) => Post::update(pool, item).await,
@@ -193,10 +272,14 @@ pub async fn update_item(object: DbObject, pool: &RpelPool) -> Result<i64, Servi
         DbObject::Rank(item) => Rank::update(pool, item).await,
         DbObject::Scope(item) => Scope::update(pool, item).await,
         DbObject::Siren(item) => Siren::update(pool, *item).await,
+        DbObject::SirenCheck(item) => Ok(SirenCheck::update(pool, item).await?),
         DbObject::SirenType(item) => SirenType::update(pool, item).await,
         DbObject::User(item) => User::update(pool, item).await,
         _ => return Err(ServiceError::BadRequest("bad item object".to_string())),
     }?;
+    if let Some(item) = &versioned {
+        history::after_update(pool, item).await?;
+    }
     Ok(res as i64)
 }
 
@@ -213,6 +296,7 @@ pub async fn delete_item(item: &Item, pool: &RpelPool) -> Result<i64, ServiceErr
         "Rank" => Rank::delete(pool, item.id).await,
         "Scope" => Scope::delete(pool, item.id).await,
         "Siren" => Siren::delete(pool, item.id).await,
+        "SirenCheck" => Ok(SirenCheck::delete(pool, item.id).await?),
         "Siren_type" => SirenType::delete(pool, item.id).await,
         "User" => User::delete(pool, item.id).await,
         _ => {
@@ -0,0 +1,296 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+
+use crate::{config, error::ServiceError, history, messages::Item, photo};
+
+const DEFAULT_THRESHOLD: f64 = 0.75;
+const NAME_WEIGHT: f64 = 0.5;
+const PHONE_WEIGHT: f64 = 0.3;
+const EMAIL_WEIGHT: f64 = 0.2;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ContactDuplicate {
+    pub id: i64,
+    pub name: Option<String>,
+    pub other_id: i64,
+    pub other_name: Option<String>,
+    pub score: f64,
+    pub name_score: f64,
+    pub phone_score: Option<f64>,
+    pub email_score: Option<f64>,
+}
+
+struct Candidate {
+    id: i64,
+    name: Option<String>,
+    normalized: String,
+    phones: HashSet<String>,
+    emails: HashSet<String>,
+}
+
+pub fn normalize_name(name: &str) -> String {
+    let mut words: Vec<String> = name
+        .to_lowercase()
+        .replace('ё', "е")
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(String::from)
+        .collect();
+    words.sort();
+    words.join(" ")
+}
+
+/// Keeps the last ten digits, so `8 (912) ...` and `+7 912 ...` match.
+pub fn normalize_phone(phone: &str) -> String {
+    let digits: Vec<char> = phone.chars().filter(char::is_ascii_digit).collect();
+    digits[digits.len().saturating_sub(10)..].iter().collect()
+}
+
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                previous
+            } else {
+                previous.min(current).min(row[j]) + 1
+            };
+            previous = current;
+        }
+    }
+    row[b.len()]
+}
+
+fn similarity(a: &str, b: &str) -> f64 {
+    let len = a.chars().count().max(b.chars().count());
+    if len == 0 {
+        return 0.0;
+    }
+    1.0 - levenshtein(a, b) as f64 / len as f64
+}
+
+fn overlap(a: &HashSet<String>, b: &HashSet<String>) -> Option<f64> {
+    if a.is_empty() || b.is_empty() {
+        None
+    } else {
+        Some(a.intersection(b).count() as f64 / a.len().min(b.len()) as f64)
+    }
+}
+
+impl Candidate {
+    fn compare(&self, other: &Candidate) -> ContactDuplicate {
+        let name_score = similarity(&self.normalized, &other.normalized);
+        let phone_score = overlap(&self.phones, &other.phones);
+        let email_score = overlap(&self.emails, &other.emails);
+        let mut total = NAME_WEIGHT * name_score;
+        let mut weight = NAME_WEIGHT;
+        if let Some(score) = phone_score {
+            total += PHONE_WEIGHT * score;
+            weight += PHONE_WEIGHT;
+        }
+        if let Some(score) = email_score {
+            total += EMAIL_WEIGHT * score;
+            weight += EMAIL_WEIGHT;
+        }
+        ContactDuplicate {
+            id: self.id,
+            name: self.name.clone(),
+            other_id: other.id,
+            other_name: other.name.clone(),
+            score: total / weight,
+            name_score,
+            phone_score,
+            email_score,
+        }
+    }
+
+    /// Keys used to limit comparison to contacts sharing a surname, phone or email.
+    fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .normalized
+            .split(' ')
+            .filter(|word| word.chars().count() > 2)
+            .map(|word| format!("n:{word}"))
+            .collect();
+        keys.extend(self.phones.iter().map(|phone| format!("p:{phone}")));
+        keys.extend(self.emails.iter().map(|email| format!("e:{email}")));
+        keys
+    }
+}
+
+impl ContactDuplicate {
+    pub async fn get_all(
+        pool: &RpelPool,
+        threshold: Option<f64>,
+    ) -> Result<Vec<ContactDuplicate>, ServiceError> {
+        let threshold = threshold
+            .unwrap_or_else(|| config::var_or("RGO_DUPLICATE_THRESHOLD", DEFAULT_THRESHOLD));
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT
+                    c.id,
+                    c.name,
+                    ARRAY(SELECT phone::text FROM phones WHERE contact_id = c.id) AS phones,
+                    ARRAY(SELECT email FROM emails WHERE contact_id = c.id) AS emails
+                FROM
+                    contacts AS c",
+                &[],
+            )
+            .await?;
+        let candidates: Vec<Candidate> = rows
+            .iter()
+            .map(|row| {
+                let name: Option<String> = row.get("name");
+                let phones: Vec<String> = row.get("phones");
+                let emails: Vec<String> = row.get("emails");
+                Candidate {
+                    id: row.get("id"),
+                    normalized: normalize_name(name.as_deref().unwrap_or_default()),
+                    name,
+                    phones: phones
+                        .iter()
+                        .map(|phone| normalize_phone(phone))
+                        .filter(|phone| !phone.is_empty())
+                        .collect(),
+                    emails: emails
+                        .iter()
+                        .map(|email| normalize_email(email))
+                        .filter(|email| !email.is_empty())
+                        .collect(),
+                }
+            })
+            .collect();
+        let mut blocks: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, candidate) in candidates.iter().enumerate() {
+            for key in candidate.keys() {
+                blocks.entry(key).or_default().push(index);
+            }
+        }
+        let mut pairs = BTreeSet::new();
+        for indexes in blocks.values() {
+            for (n, a) in indexes.iter().enumerate() {
+                for b in &indexes[n + 1..] {
+                    pairs.insert((*a.min(b), *a.max(b)));
+                }
+            }
+        }
+        let mut duplicates: Vec<ContactDuplicate> = pairs
+            .into_iter()
+            .map(|(a, b)| candidates[a].compare(&candidates[b]))
+            .filter(|duplicate| duplicate.score >= threshold)
+            .collect();
+        duplicates.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(duplicates)
+    }
+}
+
+/// Moves phones, emails, attachments, the photo and every reference of
+/// `remove` to `keep`, fills empty fields of `keep` and deletes `remove` in
+/// one transaction.
+/// Practices reference companies, not contacts, so they stay untouched.
+pub async fn merge_contacts(pool: &RpelPool, keep: i64, remove: i64) -> Result<(), ServiceError> {
+    if keep == remove {
+        return Err(ServiceError::BadRequest(format!(
+            "merge contact {keep} with itself"
+        )));
+    }
+    let item = Item {
+        name: "Contact".to_string(),
+        id: keep,
+    };
+    let removed_photo = photo::photo_hash(pool, remove).await?;
+    history::before_update(pool, &item).await?;
+    let mut client = pool.get().await?;
+    let tx = client.transaction().await?;
+    let found = tx
+        .query(
+            "SELECT id FROM contacts WHERE id = $1 OR id = $2 FOR UPDATE",
+            &[&keep, &remove],
+        )
+        .await?;
+    if found.len() != 2 {
+        return Err(ServiceError::BadRequest(format!(
+            "bad merge contacts: {keep} {remove}"
+        )));
+    }
+    tx.execute(
+        "UPDATE phones SET contact_id = $1
+        WHERE contact_id = $2
+        AND NOT EXISTS (
+            SELECT 1 FROM phones AS p
+            WHERE p.contact_id = $1 AND p.phone = phones.phone AND p.fax = phones.fax
+        )",
+        &[&keep, &remove],
+    )
+    .await?;
+    tx.execute("DELETE FROM phones WHERE contact_id = $1", &[&remove])
+        .await?;
+    tx.execute(
+        "UPDATE emails SET contact_id = $1
+        WHERE contact_id = $2
+        AND NOT EXISTS (
+            SELECT 1 FROM emails AS e
+            WHERE e.contact_id = $1 AND lower(e.email) = lower(emails.email)
+        )",
+        &[&keep, &remove],
+    )
+    .await?;
+    tx.execute("DELETE FROM emails WHERE contact_id = $1", &[&remove])
+        .await?;
+    for table in ["educations", "certificates", "sirens", "siren_checks"] {
+        tx.execute(
+            format!("UPDATE {table} SET contact_id = $1 WHERE contact_id = $2").as_str(),
+            &[&keep, &remove],
+        )
+        .await?;
+    }
+    tx.execute(
+        "UPDATE attachments SET entity_id = $1 WHERE entity = 'Contact' AND entity_id = $2",
+        &[&keep, &remove],
+    )
+    .await?;
+    // The photo of `keep` wins, the other one goes with `remove`.
+    tx.execute(
+        "UPDATE contact_photos SET contact_id = $1, updated_at = now()
+        WHERE contact_id = $2
+        AND NOT EXISTS (SELECT 1 FROM contact_photos WHERE contact_id = $1)",
+        &[&keep, &remove],
+    )
+    .await?;
+    tx.execute(
+        "UPDATE contacts AS k SET
+            company_id = COALESCE(k.company_id, r.company_id),
+            department_id = COALESCE(k.department_id, r.department_id),
+            post_id = COALESCE(k.post_id, r.post_id),
+            post_go_id = COALESCE(k.post_go_id, r.post_go_id),
+            rank_id = COALESCE(k.rank_id, r.rank_id),
+            birthday = COALESCE(k.birthday, r.birthday),
+            note = NULLIF(concat_ws(E'\\n', NULLIF(k.note, ''), NULLIF(r.note, '')), ''),
+            updated_at = now()
+        FROM
+            contacts AS r
+        WHERE
+            k.id = $1 AND r.id = $2",
+        &[&keep, &remove],
+    )
+    .await?;
+    tx.execute("DELETE FROM contacts WHERE id = $1", &[&remove])
+        .await?;
+    tx.commit().await?;
+    history::after_update(pool, &item).await?;
+    if let Some(hash) = removed_photo {
+        photo::remove_unused(pool, &hash).await?;
+    }
+    Ok(())
+}
@@ -12,10 +12,30 @@ pub enum ServiceError {
     Rpel(#[from] rpel::error::RpelError),
     #[error("Serde JSON: {0}")]
     SJError(#[from] serde_json::error::Error),
+    #[error("Pool: {0}")]
+    Pool(#[from] deadpool_postgres::PoolError),
+    #[error("Postgres: {0}")]
+    Postgres(#[from] tokio_postgres::Error),
+    #[error("PDF: {0}")]
+    Pdf(#[from] printpdf::Error),
+    #[error("Multipart: {0}")]
+    Multipart(#[from] multer::Error),
+    #[error("Image: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("LDAP: {0}")]
+    Ldap(#[from] ldap3::LdapError),
+    #[error("HTTP client: {0}")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("JWT: {0}")]
+    Jwt(#[from] jsonwebtoken::errors::Error),
+    #[error("Validation: {0:?}")]
+    Validation(Vec<crate::validate::FieldError>),
     #[error("Not auth")]
     NotAuth,
     #[error("Not permission")]
     NotPermission,
+    #[error("Too many login attempts, retry in {0} s")]
+    TooManyAttempts(u64),
     #[error("Hyper: {0}")]
     Hyper(#[from] hyper::Error),
     #[error("Hyper http: {0}")]
@@ -0,0 +1,325 @@
+use hyper::{Body, Response};
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio_postgres::Row;
+
+use crate::{error::ServiceError, services::json_response};
+
+const EARTH_RADIUS: f64 = 6_371_000.0;
+const METERS_PER_DEGREE: f64 = 111_320.0;
+const MAX_GRID_CELLS: usize = 250_000;
+
+/// Coordinates are free text, values that are not a number are skipped.
+const SIREN_POINTS: &str = "
+    SELECT
+        p.id,
+        p.num_id,
+        p.siren_type,
+        p.radius,
+        p.address,
+        p.status,
+        p.latitude,
+        p.longitude
+    FROM (
+        SELECT
+            s.id,
+            s.num_id::bigint AS num_id,
+            st.name AS siren_type,
+            st.radius::float8 AS radius,
+            s.address,
+            s.stage::bigint AS status,
+            CASE WHEN REPLACE(TRIM(s.latitude::text), ',', '.') ~ '^[-+]?[0-9]+(\\.[0-9]+)?$'
+                THEN REPLACE(TRIM(s.latitude::text), ',', '.')::float8
+            END AS latitude,
+            CASE WHEN REPLACE(TRIM(s.longitude::text), ',', '.') ~ '^[-+]?[0-9]+(\\.[0-9]+)?$'
+                THEN REPLACE(TRIM(s.longitude::text), ',', '.')::float8
+            END AS longitude
+        FROM
+            sirens AS s
+        LEFT JOIN
+            siren_types AS st ON st.id = s.siren_type_id
+    ) AS p
+    WHERE
+        p.latitude IS NOT NULL AND p.longitude IS NOT NULL";
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Point {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct BBox {
+    pub min_latitude: f64,
+    pub min_longitude: f64,
+    pub max_latitude: f64,
+    pub max_longitude: f64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub enum GeoObject {
+    SirenFeatures,
+    SirensInBox(BBox),
+    NearestSirens { point: Point, limit: i64 },
+    CoverageGaps { bbox: BBox, step: f64 },
+}
+
+#[derive(Debug, Serialize)]
+pub struct SirenPoint {
+    pub id: i64,
+    pub num_id: Option<i64>,
+    pub siren_type: Option<String>,
+    pub radius: Option<f64>,
+    pub address: Option<String>,
+    pub status: Option<i64>,
+    pub point: Point,
+}
+
+#[derive(Serialize)]
+pub struct Geometry {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub coordinates: [f64; 2],
+}
+
+#[derive(Serialize)]
+pub struct Feature {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub geometry: Geometry,
+    pub properties: Value,
+}
+
+#[derive(Serialize)]
+pub struct FeatureCollection {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub features: Vec<Feature>,
+}
+
+#[derive(Serialize)]
+pub struct WsGeoMsg {
+    pub command: String,
+    pub object: FeatureCollection,
+    pub error: String,
+}
+
+impl Point {
+    pub fn distance(&self, other: &Point) -> f64 {
+        let lat1 = self.latitude.to_radians();
+        let lat2 = other.latitude.to_radians();
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.longitude - self.longitude).to_radians();
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * a.sqrt().asin()
+    }
+
+    fn geometry(&self) -> Geometry {
+        Geometry {
+            kind: "Point",
+            coordinates: [self.longitude, self.latitude],
+        }
+    }
+}
+
+impl BBox {
+    fn check(&self) -> Result<(), ServiceError> {
+        if self.min_latitude > self.max_latitude || self.min_longitude > self.max_longitude {
+            Err(ServiceError::BadRequest(format!(
+                "bad bounding box: {self:?}"
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl SirenPoint {
+    fn from_row(row: &Row) -> Self {
+        SirenPoint {
+            id: row.get("id"),
+            num_id: row.get("num_id"),
+            siren_type: row.get("siren_type"),
+            radius: row.get("radius"),
+            address: row.get("address"),
+            status: row.get("status"),
+            point: Point {
+                latitude: row.get("latitude"),
+                longitude: row.get("longitude"),
+            },
+        }
+    }
+
+    pub async fn get_all(pool: &RpelPool) -> Result<Vec<SirenPoint>, ServiceError> {
+        let client = pool.get().await?;
+        let rows = client.query(SIREN_POINTS, &[]).await?;
+        Ok(rows.iter().map(SirenPoint::from_row).collect())
+    }
+
+    pub async fn get_in_box(pool: &RpelPool, bbox: &BBox) -> Result<Vec<SirenPoint>, ServiceError> {
+        bbox.check()?;
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                format!(
+                    "{SIREN_POINTS}
+                    AND p.latitude BETWEEN $1 AND $3
+                    AND p.longitude BETWEEN $2 AND $4"
+                )
+                .as_str(),
+                &[
+                    &bbox.min_latitude,
+                    &bbox.min_longitude,
+                    &bbox.max_latitude,
+                    &bbox.max_longitude,
+                ],
+            )
+            .await?;
+        Ok(rows.iter().map(SirenPoint::from_row).collect())
+    }
+
+    pub async fn get_nearest(
+        pool: &RpelPool,
+        point: &Point,
+        limit: i64,
+    ) -> Result<Vec<(SirenPoint, f64)>, ServiceError> {
+        let mut sirens: Vec<(SirenPoint, f64)> = SirenPoint::get_all(pool)
+            .await?
+            .into_iter()
+            .map(|siren| {
+                let distance = siren.point.distance(point);
+                (siren, distance)
+            })
+            .collect();
+        sirens.sort_by(|a, b| a.1.total_cmp(&b.1));
+        sirens.truncate(limit.max(0) as usize);
+        Ok(sirens)
+    }
+
+    fn feature(&self, distance: Option<f64>) -> Feature {
+        let mut properties = json!({
+            "num_id": self.num_id,
+            "type": self.siren_type,
+            "radius": self.radius,
+            "address": self.address,
+            "status": self.status,
+        });
+        if let Some(distance) = distance {
+            properties["distance"] = json!(distance.round());
+        }
+        Feature {
+            kind: "Feature",
+            id: Some(self.id),
+            geometry: self.point.geometry(),
+            properties,
+        }
+    }
+}
+
+impl FeatureCollection {
+    fn new(features: Vec<Feature>) -> Self {
+        FeatureCollection {
+            kind: "FeatureCollection",
+            features,
+        }
+    }
+}
+
+/// Centers of grid cells of `step` meters inside `bbox` that are not covered
+/// by the audible radius of any siren.
+pub async fn coverage_gaps(
+    pool: &RpelPool,
+    bbox: &BBox,
+    step: f64,
+) -> Result<Vec<Point>, ServiceError> {
+    bbox.check()?;
+    if !step.is_finite() || step <= 0.0 {
+        return Err(ServiceError::BadRequest(format!("bad grid step: {step}")));
+    }
+    let center_latitude = (bbox.min_latitude + bbox.max_latitude) / 2.0;
+    let lat_step = step / METERS_PER_DEGREE;
+    let lon_step = step / (METERS_PER_DEGREE * center_latitude.to_radians().cos().max(0.01));
+    let rows = ((bbox.max_latitude - bbox.min_latitude) / lat_step)
+        .ceil()
+        .max(1.0) as usize;
+    let cols = ((bbox.max_longitude - bbox.min_longitude) / lon_step)
+        .ceil()
+        .max(1.0) as usize;
+    if rows.saturating_mul(cols) > MAX_GRID_CELLS {
+        return Err(ServiceError::BadRequest(format!(
+            "grid too large: {rows}x{cols} cells"
+        )));
+    }
+    let sirens: Vec<(Point, f64)> = SirenPoint::get_all(pool)
+        .await?
+        .into_iter()
+        .filter_map(|siren| siren.radius.map(|radius| (siren.point, radius)))
+        .filter(|(_, radius)| *radius > 0.0)
+        .collect();
+    let mut gaps = Vec::new();
+    for row in 0..rows {
+        for col in 0..cols {
+            let cell = Point {
+                latitude: bbox.min_latitude + lat_step * (row as f64 + 0.5),
+                longitude: bbox.min_longitude + lon_step * (col as f64 + 0.5),
+            };
+            if !sirens
+                .iter()
+                .any(|(point, radius)| point.distance(&cell) <= *radius)
+            {
+                gaps.push(cell);
+            }
+        }
+    }
+    Ok(gaps)
+}
+
+pub async fn geo_cmd(obj: GeoObject, pool: &RpelPool) -> Result<Response<Body>, ServiceError> {
+    let (command, features) = match obj {
+        GeoObject::SirenFeatures => (
+            "SirenFeatures",
+            SirenPoint::get_all(pool)
+                .await?
+                .iter()
+                .map(|siren| siren.feature(None))
+                .collect(),
+        ),
+        GeoObject::SirensInBox(bbox) => (
+            "SirensInBox",
+            SirenPoint::get_in_box(pool, &bbox)
+                .await?
+                .iter()
+                .map(|siren| siren.feature(None))
+                .collect(),
+        ),
+        GeoObject::NearestSirens { point, limit } => (
+            "NearestSirens",
+            SirenPoint::get_nearest(pool, &point, limit)
+                .await?
+                .iter()
+                .map(|(siren, distance)| siren.feature(Some(*distance)))
+                .collect(),
+        ),
+        GeoObject::CoverageGaps { bbox, step } => (
+            "CoverageGaps",
+            coverage_gaps(pool, &bbox, step)
+                .await?
+                .iter()
+                .map(|point| Feature {
+                    kind: "Feature",
+                    id: None,
+                    geometry: point.geometry(),
+                    properties: json!({ "step": step }),
+                })
+                .collect(),
+        ),
+    };
+    json_response(json!(WsGeoMsg {
+        command: command.to_string(),
+        object: FeatureCollection::new(features),
+        error: String::new(),
+    }))
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::{config, error::ServiceError};
+
+const DEFAULT_MAX_FAILURES: u32 = 5;
+const DEFAULT_LOCKOUT_SECS: u64 = 900;
+const DEFAULT_BACKOFF_SECS: u64 = 1;
+
+struct Attempts {
+    failures: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Failed logins per IP and per username. Every failure doubles the delay
+/// before the next try, `RGO_LOGIN_MAX_FAILURES` in a row lock the key for
+/// `RGO_LOGIN_LOCKOUT_SECS`.
+#[derive(Default)]
+pub struct LoginGuard {
+    ips: Mutex<HashMap<IpAddr, Attempts>>,
+    names: Mutex<HashMap<String, Attempts>>,
+}
+
+fn lockout() -> Duration {
+    Duration::from_secs(config::var_or(
+        "RGO_LOGIN_LOCKOUT_SECS",
+        DEFAULT_LOCKOUT_SECS,
+    ))
+}
+
+fn backoff(failures: u32) -> Duration {
+    let base = config::var_or("RGO_LOGIN_BACKOFF_SECS", DEFAULT_BACKOFF_SECS);
+    Duration::from_secs(base.saturating_mul(1 << failures.saturating_sub(1).min(16))).min(lockout())
+}
+
+/// Seconds left to wait for the key.
+fn wait<K: Eq + Hash>(values: &Mutex<HashMap<K, Attempts>>, key: &K) -> u64 {
+    let Ok(values) = values.lock() else {
+        return 0;
+    };
+    let Some(attempts) = values.get(key) else {
+        return 0;
+    };
+    let until = attempts
+        .locked_until
+        .unwrap_or_else(|| attempts.last + backoff(attempts.failures));
+    until
+        .saturating_duration_since(Instant::now())
+        .as_secs_f64()
+        .ceil() as u64
+}
+
+/// Counts a failure and returns whether the key got locked by it.
+fn fail<K: Eq + Hash>(values: &Mutex<HashMap<K, Attempts>>, key: K) -> bool {
+    let Ok(mut values) = values.lock() else {
+        return false;
+    };
+    let now = Instant::now();
+    let lockout = lockout();
+    values.retain(|_, attempts| now.duration_since(attempts.last) < lockout * 2);
+    let attempts = values.entry(key).or_insert(Attempts {
+        failures: 0,
+        last: now,
+        locked_until: None,
+    });
+    attempts.failures += 1;
+    attempts.last = now;
+    attempts.locked_until = None;
+    if attempts.failures >= config::var_or("RGO_LOGIN_MAX_FAILURES", DEFAULT_MAX_FAILURES) {
+        attempts.locked_until = Some(now + lockout);
+        attempts.failures = 0;
+        return true;
+    }
+    false
+}
+
+fn clear<K: Eq + Hash>(values: &Mutex<HashMap<K, Attempts>>, key: &K) -> bool {
+    values
+        .lock()
+        .map(|mut values| values.remove(key).is_some())
+        .unwrap_or_default()
+}
+
+impl LoginGuard {
+    /// Fails while the IP or the username has to wait. An empty name checks
+    /// the IP only.
+    pub fn check(&self, ip: IpAddr, name: &str) -> Result<(), ServiceError> {
+        let mut seconds = wait(&self.ips, &ip);
+        if !name.is_empty() {
+            seconds = seconds.max(wait(&self.names, &name.to_string()));
+        }
+        if seconds > 0 {
+            warn!("login of {name:?} from {ip} throttled for {seconds} s");
+            return Err(ServiceError::TooManyAttempts(seconds));
+        }
+        Ok(())
+    }
+
+    pub fn failure(&self, ip: IpAddr, name: &str) {
+        warn!("failed login of {name:?} from {ip}");
+        if fail(&self.ips, ip) {
+            warn!("login from {ip} locked");
+        }
+        if !name.is_empty() && fail(&self.names, name.to_string()) {
+            warn!("login of {name:?} locked");
+        }
+    }
+
+    /// Clears the failures of the username. Those of the IP stay, so one
+    /// known password does not reset guessing of the others from there.
+    pub fn success(&self, name: &str) {
+        clear(&self.names, &name.to_string());
+    }
+
+    /// Unlocks an IP address or, when `key` is not one, a username.
+    pub fn unlock(&self, key: &str) -> bool {
+        match key.parse::<IpAddr>() {
+            Ok(ip) => clear(&self.ips, &ip),
+            Err(_) => clear(&self.names, &key.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_lockout() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(2), Duration::from_secs(2));
+        assert_eq!(backoff(4), Duration::from_secs(8));
+        assert_eq!(backoff(30), lockout());
+    }
+
+    #[test]
+    fn failure_delays_ip_and_name() {
+        let guard = LoginGuard::default();
+        guard.failure(ip(1), "bob");
+        assert!(matches!(
+            guard.check(ip(1), "alice"),
+            Err(ServiceError::TooManyAttempts(1))
+        ));
+        assert!(matches!(
+            guard.check(ip(2), "bob"),
+            Err(ServiceError::TooManyAttempts(1))
+        ));
+        assert!(guard.check(ip(2), "alice").is_ok());
+    }
+
+    #[test]
+    fn repeated_failures_lock_the_name() {
+        let guard = LoginGuard::default();
+        for n in 0..DEFAULT_MAX_FAILURES {
+            guard.failure(ip(n as u8 + 1), "bob");
+        }
+        assert!(matches!(
+            guard.check(ip(100), "bob"),
+            Err(ServiceError::TooManyAttempts(seconds)) if seconds > DEFAULT_LOCKOUT_SECS - 5
+        ));
+        assert!(guard.unlock("bob"));
+        assert!(guard.check(ip(100), "bob").is_ok());
+    }
+
+    #[test]
+    fn success_clears_the_name_only() {
+        let guard = LoginGuard::default();
+        guard.failure(ip(1), "bob");
+        guard.success("bob");
+        assert!(guard.check(ip(2), "bob").is_ok());
+        assert!(guard.check(ip(1), "").is_err());
+        assert!(guard.unlock(&ip(1).to_string()));
+        assert!(guard.check(ip(1), "bob").is_ok());
+    }
+}
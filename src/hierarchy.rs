@@ -0,0 +1,195 @@
+use std::collections::{HashMap, HashSet};
+
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::Row;
+
+use crate::error::ServiceError;
+
+#[derive(Clone, Copy)]
+pub enum Hierarchy {
+    Company,
+    Department,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TreeNode {
+    pub id: i64,
+    pub name: Option<String>,
+    pub parent_id: Option<i64>,
+    pub contacts: i64,
+    pub total_contacts: i64,
+    pub children: Vec<TreeNode>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PathItem {
+    pub id: i64,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ParentLink {
+    pub id: i64,
+    pub parent_id: Option<i64>,
+}
+
+impl Hierarchy {
+    fn table(self) -> &'static str {
+        match self {
+            Hierarchy::Company => "companies",
+            Hierarchy::Department => "departments",
+        }
+    }
+
+    fn contact_column(self) -> &'static str {
+        match self {
+            Hierarchy::Company => "company_id",
+            Hierarchy::Department => "department_id",
+        }
+    }
+
+    fn nodes_query(self, filter: &str) -> String {
+        format!(
+            "{filter}
+            SELECT
+                t.id,
+                t.name,
+                t.parent_id,
+                (SELECT COUNT(*) FROM contacts AS c WHERE c.{column} = t.id) AS contacts
+            FROM
+                tree AS t",
+            column = self.contact_column()
+        )
+    }
+
+    /// Full forest with per node and per subtree contact counts.
+    pub async fn get_forest(self, pool: &RpelPool) -> Result<Vec<TreeNode>, ServiceError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                self.nodes_query(&format!(
+                    "WITH tree AS (SELECT id, name, parent_id FROM {})",
+                    self.table()
+                ))
+                .as_str(),
+                &[],
+            )
+            .await?;
+        Ok(build_tree(&rows, None))
+    }
+
+    pub async fn get_subtree(
+        self,
+        pool: &RpelPool,
+        id: i64,
+    ) -> Result<Vec<TreeNode>, ServiceError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                self.nodes_query(&format!(
+                    "WITH RECURSIVE tree AS (
+                        SELECT id, name, parent_id FROM {table} WHERE id = $1
+                        UNION
+                        SELECT x.id, x.name, x.parent_id FROM {table} AS x
+                        JOIN tree AS t ON x.parent_id = t.id
+                    )",
+                    table = self.table()
+                ))
+                .as_str(),
+                &[&id],
+            )
+            .await?;
+        Ok(build_tree(&rows, Some(id)))
+    }
+
+    /// Path from the root down to `id` inclusive, for breadcrumbs.
+    pub async fn get_path(self, pool: &RpelPool, id: i64) -> Result<Vec<PathItem>, ServiceError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                format!(
+                    "WITH RECURSIVE path AS (
+                        SELECT id, name, parent_id, 0 AS depth FROM {table} WHERE id = $1
+                        UNION ALL
+                        SELECT x.id, x.name, x.parent_id, p.depth + 1 FROM {table} AS x
+                        JOIN path AS p ON x.id = p.parent_id
+                        WHERE p.depth < 100
+                    )
+                    SELECT id, name FROM path ORDER BY depth DESC",
+                    table = self.table()
+                )
+                .as_str(),
+                &[&id],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| PathItem {
+                id: row.get("id"),
+                name: row.get("name"),
+            })
+            .collect())
+    }
+
+    pub async fn set_parent(self, pool: &RpelPool, link: ParentLink) -> Result<u64, ServiceError> {
+        if let Some(parent_id) = link.parent_id {
+            if self
+                .get_path(pool, parent_id)
+                .await?
+                .iter()
+                .any(|item| item.id == link.id)
+            {
+                return Err(ServiceError::BadRequest(format!(
+                    "parent {parent_id} is inside subtree of {}",
+                    link.id
+                )));
+            }
+        }
+        let client = pool.get().await?;
+        Ok(client
+            .execute(
+                format!("UPDATE {} SET parent_id = $2 WHERE id = $1", self.table()).as_str(),
+                &[&link.id, &link.parent_id],
+            )
+            .await?)
+    }
+}
+
+/// Builds nested nodes from flat rows. With `root` only that node is
+/// returned, otherwise every node whose parent is absent becomes a root.
+fn build_tree(rows: &[Row], root: Option<i64>) -> Vec<TreeNode> {
+    let mut children: HashMap<Option<i64>, Vec<TreeNode>> = HashMap::new();
+    let ids: HashSet<i64> = rows.iter().map(|row| row.get("id")).collect();
+    for row in rows {
+        let node = TreeNode {
+            id: row.get("id"),
+            name: row.get("name"),
+            parent_id: row.get("parent_id"),
+            contacts: row.get("contacts"),
+            total_contacts: 0,
+            children: Vec::new(),
+        };
+        let key = match node.parent_id {
+            Some(parent_id) if ids.contains(&parent_id) && Some(node.id) != root => Some(parent_id),
+            _ => None,
+        };
+        children.entry(key).or_default().push(node);
+    }
+    let mut roots = children.remove(&None).unwrap_or_default();
+    for node in roots.iter_mut() {
+        attach(node, &mut children);
+    }
+    roots.sort_by(|a, b| a.name.cmp(&b.name));
+    roots
+}
+
+fn attach(node: &mut TreeNode, children: &mut HashMap<Option<i64>, Vec<TreeNode>>) {
+    let mut nodes = children.remove(&Some(node.id)).unwrap_or_default();
+    for child in nodes.iter_mut() {
+        attach(child, children);
+    }
+    nodes.sort_by(|a, b| a.name.cmp(&b.name));
+    node.total_contacts = node.contacts + nodes.iter().map(|n| n.total_contacts).sum::<i64>();
+    node.children = nodes;
+}
@@ -0,0 +1,176 @@
+use chrono::NaiveDateTime;
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio_postgres::error::SqlState;
+
+use crate::{
+    dbo::{get_item, update_item, DbObject},
+    error::ServiceError,
+    messages::Item,
+};
+
+/// Entities with kept versions. Users are left out, their rows hold keys.
+const VERSIONED: &[&str] = &[
+    "Certificate",
+    "Company",
+    "Contact",
+    "Department",
+    "Education",
+    "Kind",
+    "Post",
+    "Practice",
+    "Rank",
+    "Scope",
+    "Siren",
+    "SirenCheck",
+    "SirenType",
+];
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EntityVersion {
+    pub version: i32,
+    pub created_at: NaiveDateTime,
+    pub data: Value,
+}
+
+fn is_versioned(name: &str) -> bool {
+    VERSIONED.contains(&name)
+}
+
+/// Fields of the entity inside a serialized `DbObject`.
+fn fields(object: &DbObject) -> Result<Value, ServiceError> {
+    Ok(serde_json::to_value(object)?
+        .get_mut(object.name())
+        .map(Value::take)
+        .unwrap_or_default())
+}
+
+fn to_object(name: &str, data: Value) -> Result<DbObject, ServiceError> {
+    let mut value = Map::new();
+    value.insert(name.to_string(), data);
+    Ok(serde_json::from_value(Value::Object(value))?)
+}
+
+const SAVE_ATTEMPTS: usize = 3;
+
+/// Stores the current state of the entity as its next version, with
+/// `first` only when it has none yet. The number is taken in the insert,
+/// a concurrent save of the same number is retried.
+async fn save_current(pool: &RpelPool, item: &Item, first: bool) -> Result<(), ServiceError> {
+    let data = fields(&get_item(item, pool).await?)?;
+    // The first version is the state since the entity was created.
+    let created_at = first
+        .then(|| data.get("created_at").and_then(Value::as_str))
+        .flatten()
+        .and_then(|value| value.parse::<NaiveDateTime>().ok());
+    let client = pool.get().await?;
+    let mut attempt = 1;
+    loop {
+        match client
+            .execute(
+                "INSERT INTO entity_versions (entity, entity_id, version, data, created_at)
+                SELECT $1, $2, COALESCE(MAX(version), 0) + 1, $3, COALESCE($4::timestamp, now())
+                FROM entity_versions
+                WHERE entity = $1 AND entity_id = $2
+                HAVING NOT $5::boolean OR COUNT(*) = 0",
+                &[&item.name, &item.id, &data, &created_at, &first],
+            )
+            .await
+        {
+            Err(err)
+                if attempt < SAVE_ATTEMPTS && err.code() == Some(&SqlState::UNIQUE_VIOLATION) =>
+            {
+                attempt += 1;
+            }
+            result => return result.map(|_| ()).map_err(ServiceError::from),
+        }
+    }
+}
+
+/// Item of an object going to `update_item` when its entity is versioned.
+pub fn versioned_item(object: &DbObject) -> Result<Option<Item>, ServiceError> {
+    let name = object.name();
+    if !is_versioned(&name) {
+        return Ok(None);
+    }
+    Ok(fields(object)?
+        .get("id")
+        .and_then(Value::as_i64)
+        .map(|id| Item { name, id }))
+}
+
+/// Before the first update the state as it was is saved, so there is
+/// always a version to go back to.
+pub async fn before_update(pool: &RpelPool, item: &Item) -> Result<(), ServiceError> {
+    save_current(pool, item, true).await
+}
+
+pub async fn after_update(pool: &RpelPool, item: &Item) -> Result<(), ServiceError> {
+    save_current(pool, item, false).await
+}
+
+pub async fn get_history(pool: &RpelPool, item: &Item) -> Result<Vec<EntityVersion>, ServiceError> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            "SELECT version, created_at, data FROM entity_versions
+            WHERE entity = $1 AND entity_id = $2
+            ORDER BY version DESC",
+            &[&item.name, &item.id],
+        )
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|row| EntityVersion {
+            version: row.get("version"),
+            created_at: row.get("created_at"),
+            data: row.get("data"),
+        })
+        .collect())
+}
+
+/// The entity as it was at `at`, the latest version saved not after it.
+/// The first version holds the state since creation, so it counts from the
+/// `created_at` of its data, when the entity has one.
+pub async fn get_item_at(
+    pool: &RpelPool,
+    item: &Item,
+    at: NaiveDateTime,
+) -> Result<DbObject, ServiceError> {
+    let client = pool.get().await?;
+    let row = client
+        .query_opt(
+            "SELECT data FROM entity_versions
+            WHERE entity = $1 AND entity_id = $2
+            AND (
+                created_at <= $3
+                OR version = 1 AND COALESCE((data->>'created_at')::timestamp <= $3, true)
+            )
+            ORDER BY version DESC
+            LIMIT 1",
+            &[&item.name, &item.id, &at],
+        )
+        .await?
+        .ok_or_else(|| {
+            ServiceError::BadRequest(format!("no version of {} {} at {at}", item.name, item.id))
+        })?;
+    to_object(&item.name, row.get("data"))
+}
+
+/// Writes an old version back through `update_item`, so it is validated
+/// and becomes the newest version itself.
+pub async fn revert_item(pool: &RpelPool, item: &Item, version: i32) -> Result<i64, ServiceError> {
+    let client = pool.get().await?;
+    let row = client
+        .query_opt(
+            "SELECT data FROM entity_versions
+            WHERE entity = $1 AND entity_id = $2 AND version = $3",
+            &[&item.name, &item.id, &version],
+        )
+        .await?
+        .ok_or_else(|| {
+            ServiceError::BadRequest(format!("no version {version} of {} {}", item.name, item.id))
+        })?;
+    update_item(to_object(&item.name, row.get("data"))?, pool).await
+}
@@ -0,0 +1,348 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use log::warn;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    auth::A,
+    config,
+    error::ServiceError,
+    session::{
+        create_impersonation, create_session, kill_refresh_session, refresh_session, SessionInfo,
+    },
+    users::{load_user, Impersonator, UserData, Users},
+};
+
+const DEFAULT_ACCESS_SECS: i64 = 900;
+const DEFAULT_REFRESH_SECS: i64 = 30 * 24 * 3600;
+const DEFAULT_IMPERSONATION_SECS: i64 = 900;
+const REFRESH_LEN: usize = 48;
+
+/// Claims of an access token, enough to check a command without a lookup.
+#[derive(Deserialize, Serialize)]
+struct Claims {
+    sub: i64,
+    name: String,
+    role: i64,
+    company_id: Option<i64>,
+    department_id: Option<i64>,
+    sid: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    imp: Option<Impersonator>,
+    iat: i64,
+    exp: i64,
+}
+
+fn impersonation_secs() -> i64 {
+    config::var_or("RGO_IMPERSONATION_SECS", DEFAULT_IMPERSONATION_SECS)
+}
+
+/// Keys of the access tokens. `RGO_JWT_ALG` is `HS256` with the shared
+/// `RGO_JWT_SECRET` or `EdDSA` with the PEM files `RGO_JWT_PRIVATE_KEY` and
+/// `RGO_JWT_PUBLIC_KEY`. Instances with the same keys accept the tokens of
+/// each other.
+pub struct TokenKeys {
+    header: Header,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    validation: Validation,
+}
+
+fn random_string(len: usize) -> String {
+    thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+impl TokenKeys {
+    pub fn from_env() -> Result<Self, ServiceError> {
+        match config::var_or("RGO_JWT_ALG", String::from("HS256")).as_str() {
+            "HS256" => {
+                let secret = dotenv::var("RGO_JWT_SECRET")
+                    .ok()
+                    .filter(|secret| !secret.is_empty())
+                    .unwrap_or_else(|| {
+                        warn!("RGO_JWT_SECRET is not set, tokens are valid for this instance only");
+                        random_string(64)
+                    });
+                Ok(TokenKeys::new(
+                    Algorithm::HS256,
+                    EncodingKey::from_secret(secret.as_bytes()),
+                    DecodingKey::from_secret(secret.as_bytes()),
+                ))
+            }
+            "EdDSA" => {
+                let private = std::fs::read(
+                    dotenv::var("RGO_JWT_PRIVATE_KEY").expect("RGO_JWT_PRIVATE_KEY must be set"),
+                )?;
+                let public = std::fs::read(
+                    dotenv::var("RGO_JWT_PUBLIC_KEY").expect("RGO_JWT_PUBLIC_KEY must be set"),
+                )?;
+                Ok(TokenKeys::new(
+                    Algorithm::EdDSA,
+                    EncodingKey::from_ed_pem(&private)?,
+                    DecodingKey::from_ed_pem(&public)?,
+                ))
+            }
+            alg => Err(ServiceError::BadRequest(format!(
+                "unsupported RGO_JWT_ALG {alg}"
+            ))),
+        }
+    }
+
+    fn new(alg: Algorithm, encoding: EncodingKey, decoding: DecodingKey) -> Self {
+        TokenKeys {
+            header: Header::new(alg),
+            encoding,
+            decoding,
+            validation: Validation::new(alg),
+        }
+    }
+
+    /// Token of the user, one of an impersonation can not outlive it.
+    pub fn access_token(&self, user: &UserData, sid: i64) -> Result<String, ServiceError> {
+        let iat = now();
+        let ttl = config::var_or("RGO_ACCESS_TOKEN_SECS", DEFAULT_ACCESS_SECS);
+        let claims = Claims {
+            sub: user.id,
+            name: user.name.clone(),
+            role: user.role,
+            company_id: user.company_id,
+            department_id: user.department_id,
+            sid,
+            imp: user.impersonator.clone(),
+            iat,
+            exp: iat
+                + match user.impersonator {
+                    Some(_) => ttl.min(impersonation_secs()),
+                    None => ttl,
+                },
+        };
+        Ok(encode(&self.header, &claims, &self.encoding)?)
+    }
+
+    /// User and session of a valid and unexpired access token.
+    pub fn verify(&self, token: &str) -> Option<(UserData, i64)> {
+        let claims = decode::<Claims>(token, &self.decoding, &self.validation)
+            .ok()?
+            .claims;
+        Some((
+            UserData {
+                id: claims.sub,
+                name: claims.name,
+                key: String::new(),
+                role: claims.role,
+                company_id: claims.company_id,
+                department_id: claims.department_id,
+                impersonator: claims.imp,
+            },
+            claims.sid,
+        ))
+    }
+}
+
+/// New session with access and refresh tokens of a logged in user.
+pub async fn issue_tokens(
+    pool: &RpelPool,
+    users: &Users,
+    user: &UserData,
+    info: &SessionInfo,
+) -> Result<A, ServiceError> {
+    let sid = create_session(pool, user.id, info).await?;
+    session_tokens(pool, users, user, sid).await
+}
+
+async fn session_tokens(
+    pool: &RpelPool,
+    users: &Users,
+    user: &UserData,
+    sid: i64,
+) -> Result<A, ServiceError> {
+    let refresh = random_string(REFRESH_LEN);
+    let client = pool.get().await?;
+    client
+        .execute(
+            "INSERT INTO refresh_tokens (user_id, session_id, token_hash, expires_at)
+            VALUES ($1, $2, $3, now() + $4::bigint * interval '1 second')",
+            &[
+                &user.id,
+                &sid,
+                &hash_token(&refresh),
+                &config::var_or("RGO_REFRESH_TOKEN_SECS", DEFAULT_REFRESH_SECS),
+            ],
+        )
+        .await?;
+    Ok(A {
+        t: users.access_token(user, sid)?,
+        r: user.role,
+        f: refresh,
+    })
+}
+
+/// Access token of an impersonation. It has no refresh token, so it ends
+/// with its short expiry.
+pub async fn impersonation_token(
+    pool: &RpelPool,
+    users: &Users,
+    user: &UserData,
+    info: &SessionInfo,
+) -> Result<A, ServiceError> {
+    let admin = user
+        .impersonator
+        .as_ref()
+        .ok_or(ServiceError::NotPermission)?;
+    let sid = create_impersonation(pool, user.id, admin.id, impersonation_secs(), info).await?;
+    Ok(A {
+        t: users.access_token(user, sid)?,
+        r: user.role,
+        f: String::new(),
+    })
+}
+
+/// Spends a refresh token for new tokens of the same session. The user is
+/// read again, so role and scope changes apply from the next refresh.
+pub async fn refresh_tokens(
+    pool: &RpelPool,
+    users: &Users,
+    refresh: &str,
+    info: &SessionInfo,
+) -> Result<A, ServiceError> {
+    let client = pool.get().await?;
+    let row = client
+        .query_opt(
+            "UPDATE refresh_tokens SET revoked = true
+            WHERE token_hash = $1 AND NOT revoked AND expires_at > now()
+            RETURNING user_id, session_id",
+            &[&hash_token(refresh)],
+        )
+        .await?
+        .ok_or(ServiceError::NotAuth)?;
+    let sid: i64 = row
+        .get::<_, Option<i64>>("session_id")
+        .ok_or(ServiceError::NotAuth)?;
+    refresh_session(pool, sid, info).await?;
+    let user = load_user(pool, row.get("user_id"))
+        .await?
+        .ok_or(ServiceError::NotAuth)?;
+    session_tokens(pool, users, &user, sid).await
+}
+
+/// Ends the session of the refresh token.
+pub async fn revoke_refresh_token(
+    pool: &RpelPool,
+    users: &Users,
+    refresh: &str,
+) -> Result<u64, ServiceError> {
+    kill_refresh_session(pool, users.sessions(), &hash_token(refresh)).await
+}
+
+/// Revokes all refresh tokens of the user.
+pub async fn revoke_user_tokens(pool: &RpelPool, user_id: i64) -> Result<u64, ServiceError> {
+    let client = pool.get().await?;
+    Ok(client
+        .execute(
+            "UPDATE refresh_tokens SET revoked = true WHERE user_id = $1 AND NOT revoked",
+            &[&user_id],
+        )
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(secret: &str) -> TokenKeys {
+        TokenKeys::new(
+            Algorithm::HS256,
+            EncodingKey::from_secret(secret.as_bytes()),
+            DecodingKey::from_secret(secret.as_bytes()),
+        )
+    }
+
+    fn user() -> UserData {
+        UserData {
+            id: 7,
+            name: "user".to_string(),
+            key: "secret".to_string(),
+            role: 63,
+            company_id: Some(2),
+            department_id: None,
+            impersonator: None,
+        }
+    }
+
+    #[test]
+    fn token_round_trip() {
+        let keys = keys("test");
+        let token = keys.access_token(&user(), 11).unwrap();
+        let (verified, sid) = keys.verify(&token).unwrap();
+        assert_eq!(sid, 11);
+        assert_eq!(verified.id, 7);
+        assert_eq!(verified.name, "user");
+        assert_eq!(verified.role, 63);
+        assert_eq!(verified.company_id, Some(2));
+        assert_eq!(verified.department_id, None);
+        assert!(verified.key.is_empty());
+        assert!(verified.impersonator.is_none());
+    }
+
+    #[test]
+    fn token_of_other_keys_is_refused() {
+        let token = keys("test").access_token(&user(), 11).unwrap();
+        assert!(keys("other").verify(&token).is_none());
+        assert!(keys("test").verify("not a token").is_none());
+    }
+
+    #[test]
+    fn expired_token_is_refused() {
+        let keys = keys("test");
+        let iat = now() - 3600;
+        let claims = Claims {
+            sub: 7,
+            name: "user".to_string(),
+            role: 63,
+            company_id: None,
+            department_id: None,
+            sid: 11,
+            imp: None,
+            iat,
+            exp: iat + 600,
+        };
+        let token = encode(&keys.header, &claims, &keys.encoding).unwrap();
+        assert!(keys.verify(&token).is_none());
+    }
+
+    #[test]
+    fn impersonation_keeps_admin_and_short_expiry() {
+        let keys = keys("test");
+        let mut user = user();
+        user.impersonator = Some(Impersonator {
+            id: 1,
+            name: "admin".to_string(),
+        });
+        let token = keys.access_token(&user, 11).unwrap();
+        let claims = decode::<Claims>(&token, &keys.decoding, &keys.validation)
+            .unwrap()
+            .claims;
+        assert!(claims.exp - claims.iat <= impersonation_secs());
+        let (verified, _) = keys.verify(&token).unwrap();
+        assert_eq!(verified.impersonator.map(|admin| admin.id), Some(1));
+    }
+}
@@ -0,0 +1,191 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ldap3::{dn_escape, ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use log::warn;
+use rpel::RpelPool;
+
+use crate::{
+    auth::AuthBackend,
+    config,
+    error::ServiceError,
+    users::{provision_user, UserData},
+};
+
+const DEFAULT_URL: &str = "ldap://localhost:389";
+const DEFAULT_USER_DN: &str = "uid={username},ou=people,dc=example,dc=org";
+const DEFAULT_BASE: &str = "dc=example,dc=org";
+const DEFAULT_GROUP_FILTER: &str = "(&(objectClass=groupOfNames)(member={dn}))";
+const DEFAULT_TIMEOUT_SECS: u64 = 5;
+const ISSUER: &str = "ldap";
+
+/// Bind as the user to the directory and take the role from the groups the
+/// user is a member of.
+///
+/// `RGO_LDAP_USER_DN` is the DN template of the bind, `RGO_LDAP_GROUP_FILTER`
+/// finds the groups under `RGO_LDAP_BASE`, `{username}` and `{dn}` are
+/// replaced in both. `RGO_LDAP_GROUP_ROLES` maps group `cn` to roles as
+/// `cn:role` pairs, the highest role of the groups is taken. A user without
+/// a mapped group can not log in. Users are linked by their DN, a user of the
+/// table with the same name is not taken over.
+pub struct LdapBackend {
+    url: String,
+    starttls: bool,
+    timeout: Duration,
+    user_dn: String,
+    base: String,
+    group_filter: String,
+    group_roles: Vec<(String, i64)>,
+}
+
+impl LdapBackend {
+    pub fn from_env() -> Self {
+        LdapBackend {
+            url: config::var_or("RGO_LDAP_URL", String::from(DEFAULT_URL)),
+            starttls: config::var_or("RGO_LDAP_STARTTLS", false),
+            timeout: Duration::from_secs(config::var_or(
+                "RGO_LDAP_TIMEOUT_SECS",
+                DEFAULT_TIMEOUT_SECS,
+            )),
+            user_dn: config::var_or("RGO_LDAP_USER_DN", String::from(DEFAULT_USER_DN)),
+            base: config::var_or("RGO_LDAP_BASE", String::from(DEFAULT_BASE)),
+            group_filter: config::var_or(
+                "RGO_LDAP_GROUP_FILTER",
+                String::from(DEFAULT_GROUP_FILTER),
+            ),
+            group_roles: config::var_pairs("RGO_LDAP_GROUP_ROLES"),
+        }
+    }
+
+    fn group_role(&self, groups: &[String]) -> i64 {
+        self.group_roles
+            .iter()
+            .filter(|(cn, _)| groups.iter().any(|group| group.eq_ignore_ascii_case(cn)))
+            .fold(0, |role, (_, group_role)| role.max(*group_role))
+    }
+
+    fn dn(&self, name: &str) -> String {
+        self.user_dn.replace("{username}", &dn_escape(name))
+    }
+
+    /// Group names of the user, `None` when the bind fails.
+    async fn groups(&self, name: &str, key: &str) -> Result<Option<Vec<String>>, ServiceError> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(self.timeout)
+            .set_starttls(self.starttls);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.url).await?;
+        ldap3::drive!(conn);
+        let dn = self.dn(name);
+        if ldap.simple_bind(&dn, key).await?.rc != 0 {
+            ldap.unbind().await.ok();
+            return Ok(None);
+        }
+        let filter = self
+            .group_filter
+            .replace("{username}", &ldap_escape(name))
+            .replace("{dn}", &ldap_escape(&dn));
+        let (entries, _) = ldap
+            .search(&self.base, Scope::Subtree, &filter, vec!["cn"])
+            .await?
+            .success()?;
+        ldap.unbind().await?;
+        Ok(Some(
+            entries
+                .into_iter()
+                .map(SearchEntry::construct)
+                .flat_map(|entry| entry.attrs.get("cn").cloned().unwrap_or_default())
+                .collect(),
+        ))
+    }
+}
+
+#[async_trait]
+impl AuthBackend for LdapBackend {
+    async fn login(
+        &self,
+        pool: &RpelPool,
+        name: &str,
+        key: &str,
+    ) -> Result<Option<UserData>, ServiceError> {
+        // An empty password is an unauthenticated bind, which succeeds.
+        if name.is_empty() || key.is_empty() {
+            return Ok(None);
+        }
+        let Some(groups) = self.groups(name, key).await? else {
+            return Ok(None);
+        };
+        let role = self.group_role(&groups);
+        if role == 0 {
+            warn!("ldap user {name:?} has no mapped group");
+            return Ok(None);
+        }
+        let Some(mut user) = provision_user(pool, ISSUER, &self.dn(name), name, role).await? else {
+            return Ok(None);
+        };
+        if user.role != role {
+            // Refreshed tokens take the role from the table.
+            let client = pool.get().await?;
+            client
+                .execute(
+                    "UPDATE users SET role = $2 WHERE id = $1",
+                    &[&user.id, &role],
+                )
+                .await?;
+            user.role = role;
+        }
+        Ok(Some(user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend() -> LdapBackend {
+        LdapBackend {
+            group_roles: vec![
+                ("readers".to_string(), 6),
+                ("editors".to_string(), 62),
+                ("admins".to_string(), 1023),
+            ],
+            ..LdapBackend::from_env()
+        }
+    }
+
+    fn groups(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn group_role_is_the_highest() {
+        let backend = backend();
+        assert_eq!(backend.group_role(&groups(&["readers", "EDITORS"])), 62);
+        assert_eq!(backend.group_role(&groups(&["editors", "readers"])), 62);
+        assert_eq!(backend.group_role(&groups(&["admins", "readers"])), 1023);
+        assert_eq!(backend.group_role(&groups(&["other"])), 0);
+    }
+
+    #[test]
+    fn dn_escapes_the_name() {
+        let backend = backend();
+        assert_eq!(
+            backend.dn("a,b=c"),
+            DEFAULT_USER_DN.replace("{username}", "a\\2cb\\3dc")
+        );
+    }
+
+    /// Needs the directory of `tests/fixtures/ldap/users.ldif`.
+    #[test]
+    #[ignore]
+    fn binds_and_reads_groups_of_local_openldap() {
+        let backend = backend();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let alice = backend.groups("alice", "alice-password").await.unwrap();
+            assert_eq!(backend.group_role(&alice.unwrap()), 62);
+            let bob = backend.groups("bob", "bob-password").await.unwrap();
+            assert_eq!(backend.group_role(&bob.unwrap()), 6);
+            assert!(backend.groups("bob", "wrong").await.unwrap().is_none());
+        });
+    }
+}
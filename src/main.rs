@@ -1,4 +1,5 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use env_logger::Env;
 use error::ServiceError;
@@ -6,19 +7,57 @@ use hyper::Server;
 use routerify::{Middleware, Router, RouterService};
 use rpel::{get_pool, RpelPool};
 
-use services::{check_auth, enable_cors_all_middleware_handler, jsonpost, logger, login};
+use auth::{backends, AuthBackend};
+use cors::CorsPolicy;
+use guard::LoginGuard;
+use migrations::migrate;
+use oidc::OidcPending;
+use services::{
+    check_auth, cors_middleware_handler, download, jsonpost, logger, login, login_totp, logout,
+    oidc_callback, oidc_login, photo, preflight, refresh, upload, upload_photo,
+};
+use totp::TotpPending;
 use users::Users;
 
+mod access;
+mod apikey;
+mod attachment;
 mod auth;
+mod birthday;
+mod config;
+mod cors;
+mod dashboard;
 mod dbo;
+mod duplicate;
 mod error;
+mod geo;
+mod guard;
+mod hierarchy;
+mod history;
+mod jwt;
+mod ldap;
 mod messages;
+mod migrations;
+mod oidc;
+mod orgchart;
+mod pdf;
+mod photo;
+mod report;
 mod services;
+mod session;
+mod siren_check;
+mod totp;
 mod users;
+mod validate;
 
 pub struct State {
     pub pool: RpelPool,
     pub users: Users,
+    pub totp: Arc<TotpPending>,
+    pub guard: Arc<LoginGuard>,
+    pub auth: Arc<Vec<Box<dyn AuthBackend>>>,
+    pub oidc: Arc<OidcPending>,
+    pub cors: CorsPolicy,
 }
 
 async fn run_server() -> Result<(), ServiceError> {
@@ -27,15 +66,34 @@ async fn run_server() -> Result<(), ServiceError> {
     let addr = dotenv::var("RGO_ADDR").expect("RGO_ADDR must be set");
     let pg_cfg = dotenv::var("RGO_DB").expect("RGO_DB must be set");
     let pool = get_pool(&pg_cfg)?;
-    let users = Users::new(&pool).await?;
+    migrate(&pool).await?;
+    let users = Users::new()?;
 
     let router = Router::builder()
-        .data(State { pool, users })
+        .data(State {
+            pool,
+            users,
+            totp: Arc::default(),
+            guard: Arc::default(),
+            auth: Arc::new(backends()),
+            oidc: Arc::default(),
+            cors: CorsPolicy::from_env(),
+        })
         .middleware(Middleware::pre(logger))
-        .middleware(Middleware::post(enable_cors_all_middleware_handler))
+        .middleware(Middleware::post_with_info(cors_middleware_handler))
         .post("/go/check", check_auth)
         .post("/go/login", login)
+        .post("/go/login/totp", login_totp)
+        .post("/go/refresh", refresh)
+        .post("/go/logout", logout)
+        .get("/go/oidc/login", oidc_login)
+        .get("/go/oidc/callback", oidc_callback)
         .post("/go/json", jsonpost)
+        .post("/go/download", download)
+        .post("/go/upload", upload)
+        .post("/go/photo", upload_photo)
+        .get("/go/photo/:id/:size", photo)
+        .options("/*", preflight)
         .build()?;
 
     let service = RouterService::new(router)?;
@@ -1,10 +1,16 @@
+use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 
-use crate::{dbo::DbObject, error::ServiceError, users::UserObject};
+use crate::{
+    attachment::AttachmentObject, dbo::DbObject, error::ServiceError, geo::GeoObject,
+    orgchart::OrgChartParams, pdf::PdfObject, report::ReportParams, totp::TotpObject,
+    users::UserObject, validate::FieldError,
+};
 
 #[derive(Deserialize)]
 pub struct ClientMessage {
     pub command: Command,
+    #[serde(default)]
     pub addon: String,
 }
 
@@ -22,6 +28,18 @@ pub enum Command {
     UpdateItem(DbObject),
     DeleteItem(Item),
     User(UserObject),
+    Totp(TotpObject),
+    Geo(GeoObject),
+    Dashboard,
+    Report(ReportParams),
+    Pdf(PdfObject),
+    FindDuplicates(Option<f64>),
+    MergeContacts { keep: i64, remove: i64 },
+    OrgChart(OrgChartParams),
+    Attachment(AttachmentObject),
+    GetHistory(Item),
+    GetItemAt(Item, NaiveDateTime),
+    RevertItem(Item, i32),
 }
 
 #[derive(Serialize)]
@@ -30,6 +48,8 @@ pub struct WsMsg {
     pub name: String,
     pub object: DbObject,
     pub error: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
 }
 
 impl WsMsg {
@@ -40,12 +60,21 @@ impl WsMsg {
                 name,
                 object,
                 error: String::new(),
+                errors: Vec::new(),
+            },
+            Err(ServiceError::Validation(errors)) => WsMsg {
+                command: command.to_string(),
+                name,
+                object: DbObject::Null,
+                error: String::from("Validation"),
+                errors,
             },
             Err(err) => WsMsg {
                 command: command.to_string(),
                 name,
                 object: DbObject::Null,
                 error: err.to_string(),
+                errors: Vec::new(),
             },
         }
     }
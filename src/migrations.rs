@@ -0,0 +1,93 @@
+use std::collections::HashSet;
+
+use log::info;
+use rpel::RpelPool;
+
+use crate::error::ServiceError;
+
+const MIGRATIONS: &[(&str, &str)] = &[
+    (
+        "0001_siren_checks",
+        include_str!("../migrations/0001_siren_checks.sql"),
+    ),
+    (
+        "0002_hierarchy",
+        include_str!("../migrations/0002_hierarchy.sql"),
+    ),
+    (
+        "0003_attachments",
+        include_str!("../migrations/0003_attachments.sql"),
+    ),
+    (
+        "0004_contact_photos",
+        include_str!("../migrations/0004_contact_photos.sql"),
+    ),
+    (
+        "0005_entity_versions",
+        include_str!("../migrations/0005_entity_versions.sql"),
+    ),
+    (
+        "0006_user_scopes",
+        include_str!("../migrations/0006_user_scopes.sql"),
+    ),
+    (
+        "0007_user_totp",
+        include_str!("../migrations/0007_user_totp.sql"),
+    ),
+    (
+        "0008_api_keys",
+        include_str!("../migrations/0008_api_keys.sql"),
+    ),
+    (
+        "0009_refresh_tokens",
+        include_str!("../migrations/0009_refresh_tokens.sql"),
+    ),
+    (
+        "0010_sessions",
+        include_str!("../migrations/0010_sessions.sql"),
+    ),
+    (
+        "0011_impersonation",
+        include_str!("../migrations/0011_impersonation.sql"),
+    ),
+    (
+        "0012_users_name",
+        include_str!("../migrations/0012_users_name.sql"),
+    ),
+    (
+        "0013_user_identities",
+        include_str!("../migrations/0013_user_identities.sql"),
+    ),
+];
+
+/// Applies the migrations not yet listed in `schema_migrations`, each in its
+/// own transaction together with its record.
+pub async fn migrate(pool: &RpelPool) -> Result<(), ServiceError> {
+    let mut client = pool.get().await?;
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                name text PRIMARY KEY,
+                applied_at timestamp without time zone DEFAULT now()
+            )",
+        )
+        .await?;
+    let applied: HashSet<String> = client
+        .query("SELECT name FROM schema_migrations", &[])
+        .await?
+        .iter()
+        .map(|row| row.get("name"))
+        .collect();
+    for (name, sql) in MIGRATIONS {
+        if applied.contains(*name) {
+            continue;
+        }
+        info!("apply migration {name}");
+        let tx = client.transaction().await?;
+        tx.batch_execute(sql).await?;
+        tx.execute("INSERT INTO schema_migrations (name) VALUES ($1)", &[name])
+            .await?;
+        tx.commit().await?;
+    }
+    Ok(())
+}
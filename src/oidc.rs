@@ -0,0 +1,470 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use data_encoding::BASE64URL_NOPAD;
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use rpel::RpelPool;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use url::{form_urlencoded, Url};
+
+use crate::{
+    config,
+    error::ServiceError,
+    users::{provision_user, UserData},
+};
+
+const PENDING_TTL: Duration = Duration::from_secs(600);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_SCOPES: &str = "openid profile email";
+const DEFAULT_USERNAME_CLAIM: &str = "preferred_username";
+
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenReply {
+    id_token: String,
+}
+
+/// Provider settings. `RGO_OIDC_ISSUER`, `RGO_OIDC_CLIENT_ID` and
+/// `RGO_OIDC_REDIRECT_URI` are required. Users are linked by the issuer and
+/// the `sub` claim of the ID token, a new user gets the name of the
+/// `RGO_OIDC_USERNAME_CLAIM` claim and `RGO_OIDC_DEFAULT_ROLE`, with the
+/// default zero unknown users are refused.
+struct OidcConfig {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    redirect_uri: String,
+    scopes: String,
+    username_claim: String,
+    default_role: i64,
+}
+
+/// Provider user of a verified ID token.
+struct Identity {
+    issuer: String,
+    subject: String,
+    name: String,
+}
+
+struct Pending {
+    nonce: String,
+    verifier: String,
+    created: Instant,
+}
+
+/// Authorization requests sent to the provider, keyed by their `state`. Like
+/// pending TOTP logins they live in the memory of the instance, the provider
+/// has to redirect back to the instance the login started on.
+#[derive(Default)]
+pub struct OidcPending {
+    values: Mutex<HashMap<String, Pending>>,
+}
+
+fn random_string(len: usize) -> String {
+    thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn required(key: &str) -> Result<String, ServiceError> {
+    dotenv::var(key)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+        .ok_or_else(|| ServiceError::BadRequest(format!("OIDC login needs {key}")))
+}
+
+impl OidcConfig {
+    fn from_env() -> Result<Self, ServiceError> {
+        Ok(OidcConfig {
+            issuer: required("RGO_OIDC_ISSUER")?,
+            client_id: required("RGO_OIDC_CLIENT_ID")?,
+            client_secret: config::var_or("RGO_OIDC_CLIENT_SECRET", String::new()),
+            redirect_uri: required("RGO_OIDC_REDIRECT_URI")?,
+            scopes: config::var_or("RGO_OIDC_SCOPES", String::from(DEFAULT_SCOPES)),
+            username_claim: config::var_or(
+                "RGO_OIDC_USERNAME_CLAIM",
+                String::from(DEFAULT_USERNAME_CLAIM),
+            ),
+            default_role: config::var_or("RGO_OIDC_DEFAULT_ROLE", 0),
+        })
+    }
+
+    fn client(&self) -> Result<reqwest::Client, ServiceError> {
+        Ok(reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?)
+    }
+
+    async fn discover(&self, client: &reqwest::Client) -> Result<Discovery, ServiceError> {
+        let discovery: Discovery = client
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                self.issuer.trim_end_matches('/')
+            ))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if discovery.issuer.trim_end_matches('/') != self.issuer.trim_end_matches('/') {
+            return Err(ServiceError::BadRequest(format!(
+                "OIDC issuer {} does not match {}",
+                discovery.issuer, self.issuer
+            )));
+        }
+        Ok(discovery)
+    }
+}
+
+/// Claims of a valid ID token signed by the key of its `kid` in the provider
+/// JWKS.
+async fn verify_id_token(
+    client: &reqwest::Client,
+    config: &OidcConfig,
+    discovery: &Discovery,
+    id_token: &str,
+) -> Result<HashMap<String, Value>, ServiceError> {
+    let header = decode_header(id_token)?;
+    // Shared secret algorithms would let the client secret sign tokens.
+    if matches!(
+        header.alg,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    ) {
+        return Err(ServiceError::NotAuth);
+    }
+    let jwks: JwkSet = client
+        .get(&discovery.jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let jwk = header
+        .kid
+        .as_ref()
+        .and_then(|kid| jwks.find(kid))
+        .ok_or(ServiceError::NotAuth)?;
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&discovery.issuer]);
+    validation.set_audience(&[&config.client_id]);
+    Ok(decode(id_token, &DecodingKey::from_jwk(jwk)?, &validation)?.claims)
+}
+
+impl OidcPending {
+    fn insert(&self, state: String, pending: Pending) {
+        if let Ok(mut values) = self.values.lock() {
+            values.retain(|_, pending| pending.created.elapsed() < PENDING_TTL);
+            values.insert(state, pending);
+        }
+    }
+
+    fn take(&self, state: &str) -> Option<Pending> {
+        let pending = self.values.lock().ok()?.remove(state)?;
+        (pending.created.elapsed() < PENDING_TTL).then_some(pending)
+    }
+
+    /// Authorization endpoint of the provider for a new login, with PKCE.
+    pub async fn login_url(&self) -> Result<String, ServiceError> {
+        self.start(&OidcConfig::from_env()?).await
+    }
+
+    async fn start(&self, config: &OidcConfig) -> Result<String, ServiceError> {
+        let discovery = config.discover(&config.client()?).await?;
+        let state = random_string(32);
+        let nonce = random_string(32);
+        let verifier = random_string(64);
+        let challenge = BASE64URL_NOPAD.encode(&Sha256::digest(verifier.as_bytes()));
+        let url = Url::parse_with_params(
+            &discovery.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &config.client_id),
+                ("redirect_uri", &config.redirect_uri),
+                ("scope", &config.scopes),
+                ("state", &state),
+                ("nonce", &nonce),
+                ("code_challenge", &challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|err| ServiceError::BadRequest(format!("OIDC authorization endpoint: {err}")))?;
+        self.insert(
+            state,
+            Pending {
+                nonce,
+                verifier,
+                created: Instant::now(),
+            },
+        );
+        Ok(url.into())
+    }
+
+    /// Exchanges the code of the provider redirect for an ID token and
+    /// returns the user linked to it.
+    pub async fn callback(&self, pool: &RpelPool, query: &str) -> Result<UserData, ServiceError> {
+        let config = OidcConfig::from_env()?;
+        let identity = self.identity(&config, query).await?;
+        provision_user(
+            pool,
+            &identity.issuer,
+            &identity.subject,
+            &identity.name,
+            config.default_role,
+        )
+        .await?
+        .ok_or(ServiceError::NotAuth)
+    }
+
+    async fn identity(&self, config: &OidcConfig, query: &str) -> Result<Identity, ServiceError> {
+        let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        if let Some(error) = params.get("error") {
+            return Err(ServiceError::BadRequest(format!(
+                "OIDC provider: {error} {}",
+                params
+                    .get("error_description")
+                    .map(String::as_str)
+                    .unwrap_or_default()
+            )));
+        }
+        let pending = params
+            .get("state")
+            .and_then(|state| self.take(state))
+            .ok_or(ServiceError::NotAuth)?;
+        let code = params
+            .get("code")
+            .ok_or_else(|| ServiceError::BadRequest("OIDC callback without code".to_string()))?;
+        let client = config.client()?;
+        let discovery = config.discover(&client).await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &config.redirect_uri),
+            ("client_id", &config.client_id),
+            ("code_verifier", &pending.verifier),
+        ];
+        if !config.client_secret.is_empty() {
+            form.push(("client_secret", &config.client_secret));
+        }
+        let reply: TokenReply = client
+            .post(&discovery.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let claims = verify_id_token(&client, config, &discovery, &reply.id_token).await?;
+        if claims.get("nonce").and_then(Value::as_str) != Some(pending.nonce.as_str()) {
+            return Err(ServiceError::NotAuth);
+        }
+        let claim = |key: &str| {
+            claims
+                .get(key)
+                .and_then(Value::as_str)
+                .filter(|value| !value.is_empty())
+                .map(String::from)
+                .ok_or(ServiceError::NotAuth)
+        };
+        Ok(Identity {
+            subject: claim("sub")?,
+            name: claim(&config.username_claim)?,
+            issuer: discovery.issuer,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server, StatusCode,
+    };
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    const CLIENT_ID: &str = "rgo";
+
+    /// Local provider, it signs ID tokens with the key of
+    /// `tests/fixtures/oidc`, `jwks.json` there is the public half.
+    struct MockProvider {
+        issuer: String,
+        kid: Option<String>,
+        nonce: Mutex<String>,
+    }
+
+    impl MockProvider {
+        /// Serves on a free local port of the runtime.
+        fn start(rt: &Runtime, kid: Option<&str>) -> Arc<MockProvider> {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let provider = Arc::new(MockProvider {
+                issuer: format!("http://{}", listener.local_addr().unwrap()),
+                kid: kid.map(String::from),
+                nonce: Mutex::default(),
+            });
+            let served = provider.clone();
+            let _context = rt.enter();
+            let server = Server::from_tcp(listener)
+                .unwrap()
+                .serve(make_service_fn(move |_| {
+                    let provider = served.clone();
+                    async move {
+                        Ok::<_, Infallible>(service_fn(move |req| {
+                            let reply = provider.reply(req);
+                            async move { Ok::<_, Infallible>(reply) }
+                        }))
+                    }
+                }));
+            rt.spawn(server);
+            provider
+        }
+
+        fn id_token(&self) -> String {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let mut header = Header::new(Algorithm::EdDSA);
+            header.kid = self.kid.clone();
+            let claims = json!({
+                "iss": self.issuer,
+                "aud": CLIENT_ID,
+                "sub": "subject-1",
+                "preferred_username": "alice",
+                "nonce": *self.nonce.lock().unwrap(),
+                "iat": now,
+                "exp": now + 300,
+            });
+            let key =
+                EncodingKey::from_ed_pem(include_bytes!("../tests/fixtures/oidc/ed25519.pem"))
+                    .unwrap();
+            encode(&header, &claims, &key).unwrap()
+        }
+
+        fn reply(&self, req: Request<Body>) -> Response<Body> {
+            let body = match req.uri().path() {
+                "/.well-known/openid-configuration" => json!({
+                    "issuer": self.issuer,
+                    "authorization_endpoint": format!("{}/authorize", self.issuer),
+                    "token_endpoint": format!("{}/token", self.issuer),
+                    "jwks_uri": format!("{}/jwks", self.issuer),
+                })
+                .to_string(),
+                "/jwks" => include_str!("../tests/fixtures/oidc/jwks.json").to_string(),
+                "/token" => {
+                    json!({ "id_token": self.id_token(), "token_type": "Bearer" }).to_string()
+                }
+                _ => {
+                    return Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::empty())
+                        .unwrap()
+                }
+            };
+            Response::builder()
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        }
+
+        fn config(&self) -> OidcConfig {
+            OidcConfig {
+                issuer: self.issuer.clone(),
+                client_id: CLIENT_ID.to_string(),
+                client_secret: String::new(),
+                redirect_uri: "http://localhost/go/oidc/callback".to_string(),
+                scopes: DEFAULT_SCOPES.to_string(),
+                username_claim: DEFAULT_USERNAME_CLAIM.to_string(),
+                default_role: 0,
+            }
+        }
+    }
+
+    /// Starts a login and returns the query of the provider redirect.
+    async fn authorize(
+        provider: &MockProvider,
+        pending: &OidcPending,
+        nonce: Option<&str>,
+    ) -> String {
+        let url = pending.start(&provider.config()).await.unwrap();
+        let params: HashMap<String, String> = Url::parse(&url)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect();
+        assert_eq!(params["client_id"], CLIENT_ID);
+        assert_eq!(params["code_challenge_method"], "S256");
+        *provider.nonce.lock().unwrap() = nonce.unwrap_or(&params["nonce"]).to_string();
+        format!("state={}&code=code", params["state"])
+    }
+
+    #[test]
+    fn login_links_issuer_and_subject() {
+        let rt = Runtime::new().unwrap();
+        let provider = MockProvider::start(&rt, Some("test"));
+        let pending = OidcPending::default();
+        rt.block_on(async {
+            let query = authorize(&provider, &pending, None).await;
+            let identity = pending.identity(&provider.config(), &query).await.unwrap();
+            assert_eq!(identity.issuer, provider.issuer);
+            assert_eq!(identity.subject, "subject-1");
+            assert_eq!(identity.name, "alice");
+            // The state is spent by the first callback.
+            assert!(matches!(
+                pending.identity(&provider.config(), &query).await,
+                Err(ServiceError::NotAuth)
+            ));
+        });
+    }
+
+    #[test]
+    fn other_nonce_is_refused() {
+        let rt = Runtime::new().unwrap();
+        let provider = MockProvider::start(&rt, Some("test"));
+        let pending = OidcPending::default();
+        rt.block_on(async {
+            let query = authorize(&provider, &pending, Some("other")).await;
+            assert!(matches!(
+                pending.identity(&provider.config(), &query).await,
+                Err(ServiceError::NotAuth)
+            ));
+        });
+    }
+
+    #[test]
+    fn token_without_known_kid_is_refused() {
+        let rt = Runtime::new().unwrap();
+        for kid in [None, Some("unknown")] {
+            let provider = MockProvider::start(&rt, kid);
+            let pending = OidcPending::default();
+            rt.block_on(async {
+                let query = authorize(&provider, &pending, None).await;
+                assert!(matches!(
+                    pending.identity(&provider.config(), &query).await,
+                    Err(ServiceError::NotAuth)
+                ));
+            });
+        }
+    }
+}
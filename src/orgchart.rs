@@ -0,0 +1,383 @@
+use std::fmt::Write;
+
+use hyper::{Body, Response};
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    error::ServiceError,
+    report::escape_html,
+    services::{file_response, json_response},
+};
+
+const CHAR_WIDTH: usize = 7;
+const LINE_HEIGHT: usize = 16;
+const PADDING: usize = 8;
+const GAP: usize = 24;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub enum OrgChartFormat {
+    #[default]
+    Json,
+    Dot,
+    Svg,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OrgChartParams {
+    pub company_id: i64,
+    #[serde(default)]
+    pub format: OrgChartFormat,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrgContact {
+    pub id: i64,
+    pub name: Option<String>,
+    pub rank_id: Option<i64>,
+    pub rank_name: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrgPost {
+    pub id: Option<i64>,
+    pub name: Option<String>,
+    pub contacts: Vec<OrgContact>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrgDepartment {
+    pub id: Option<i64>,
+    pub name: Option<String>,
+    pub posts: Vec<OrgPost>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrgChart {
+    pub company_id: i64,
+    pub company_name: Option<String>,
+    pub departments: Vec<OrgDepartment>,
+}
+
+fn label(name: &Option<String>, empty: &str) -> String {
+    match name.as_deref().map(str::trim) {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => empty.to_string(),
+    }
+}
+
+fn escape_dot(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl OrgContact {
+    fn line(&self) -> String {
+        match &self.rank_name {
+            Some(rank) if !rank.is_empty() => format!("{} ({rank})", label(&self.name, "-")),
+            _ => label(&self.name, "-"),
+        }
+    }
+}
+
+impl OrgPost {
+    /// Ranks are numbered by seniority, contacts without one come last.
+    fn sort_by_rank(&mut self) {
+        self.contacts.sort_by(|a, b| {
+            (a.rank_id.is_none(), a.rank_id, &a.name).cmp(&(
+                b.rank_id.is_none(),
+                b.rank_id,
+                &b.name,
+            ))
+        });
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let mut lines = vec![label(&self.name, "No post")];
+        lines.extend(self.contacts.iter().map(OrgContact::line));
+        lines
+    }
+}
+
+impl OrgChart {
+    /// Contacts of the company grouped by department and post, inside a post
+    /// ordered by rank and name.
+    pub async fn get(pool: &RpelPool, company_id: i64) -> Result<OrgChart, ServiceError> {
+        let client = pool.get().await?;
+        let company = client
+            .query_opt("SELECT name FROM companies WHERE id = $1", &[&company_id])
+            .await?
+            .ok_or_else(|| ServiceError::BadRequest(format!("no company {company_id}")))?;
+        let rows = client
+            .query(
+                "SELECT
+                    c.id,
+                    c.name,
+                    c.department_id,
+                    d.name AS department_name,
+                    c.post_id,
+                    p.name AS post_name,
+                    c.rank_id,
+                    r.name AS rank_name
+                FROM
+                    contacts AS c
+                LEFT JOIN
+                    departments AS d ON d.id = c.department_id
+                LEFT JOIN
+                    posts AS p ON p.id = c.post_id
+                LEFT JOIN
+                    ranks AS r ON r.id = c.rank_id
+                WHERE
+                    c.company_id = $1
+                ORDER BY
+                    d.name NULLS LAST,
+                    c.department_id NULLS LAST,
+                    p.name NULLS LAST,
+                    c.post_id NULLS LAST,
+                    c.rank_id NULLS LAST,
+                    c.name",
+                &[&company_id],
+            )
+            .await?;
+        let mut departments: Vec<OrgDepartment> = Vec::new();
+        for row in &rows {
+            let department_id: Option<i64> = row.get("department_id");
+            if departments.last().map(|d| d.id) != Some(department_id) {
+                departments.push(OrgDepartment {
+                    id: department_id,
+                    name: row.get("department_name"),
+                    posts: Vec::new(),
+                });
+            }
+            let posts = &mut departments.last_mut().unwrap().posts;
+            let post_id: Option<i64> = row.get("post_id");
+            if posts.last().map(|p| p.id) != Some(post_id) {
+                posts.push(OrgPost {
+                    id: post_id,
+                    name: row.get("post_name"),
+                    contacts: Vec::new(),
+                });
+            }
+            posts.last_mut().unwrap().contacts.push(OrgContact {
+                id: row.get("id"),
+                name: row.get("name"),
+                rank_id: row.get("rank_id"),
+                rank_name: row.get("rank_name"),
+            });
+        }
+        for department in &mut departments {
+            department.posts.iter_mut().for_each(OrgPost::sort_by_rank);
+        }
+        Ok(OrgChart {
+            company_id,
+            company_name: company.get("name"),
+            departments,
+        })
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from(
+            "digraph orgchart {\n    rankdir=TB;\n    node [shape=box, fontname=\"Helvetica\"];\n",
+        );
+        let _ = writeln!(
+            dot,
+            "    company [label=\"{}\", style=bold];",
+            escape_dot(&label(&self.company_name, "-"))
+        );
+        for (d, department) in self.departments.iter().enumerate() {
+            let _ = writeln!(
+                dot,
+                "    d{d} [label=\"{}\", style=rounded];\n    company -> d{d};",
+                escape_dot(&label(&department.name, "No department"))
+            );
+            for (p, post) in department.posts.iter().enumerate() {
+                let lines: Vec<String> = post.lines().iter().map(|l| escape_dot(l)).collect();
+                let _ = writeln!(
+                    dot,
+                    "    d{d}p{p} [label=\"{}\\l\"];\n    d{d} -> d{d}p{p};",
+                    lines.join("\\l")
+                );
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Company on top, a column per department with its posts stacked below.
+    pub fn to_svg(&self) -> String {
+        let box_width = |lines: &[String]| {
+            lines
+                .iter()
+                .map(|line| line.chars().count())
+                .max()
+                .unwrap_or_default()
+                * CHAR_WIDTH
+                + 2 * PADDING
+        };
+        let box_height = |lines: usize| lines * LINE_HEIGHT + PADDING;
+        let columns: Vec<(Vec<String>, Vec<Vec<String>>)> = self
+            .departments
+            .iter()
+            .map(|department| {
+                (
+                    vec![label(&department.name, "No department")],
+                    department.posts.iter().map(OrgPost::lines).collect(),
+                )
+            })
+            .collect();
+        let widths: Vec<usize> = columns
+            .iter()
+            .map(|(head, posts)| {
+                posts
+                    .iter()
+                    .map(|lines| box_width(lines))
+                    .chain([box_width(head)])
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+        let company = vec![label(&self.company_name, "-")];
+        let total_width = (widths.iter().sum::<usize>() + GAP * widths.len().saturating_sub(1))
+            .max(box_width(&company))
+            + 2 * GAP;
+        let department_y = GAP + box_height(1) + GAP;
+        let total_height = columns
+            .iter()
+            .map(|(_, posts)| {
+                department_y
+                    + box_height(1)
+                    + posts
+                        .iter()
+                        .map(|lines| GAP / 2 + box_height(lines.len()))
+                        .sum::<usize>()
+            })
+            .max()
+            .unwrap_or(department_y)
+            + GAP;
+
+        let mut svg = String::new();
+        let _ = writeln!(
+            svg,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{total_width}\" height=\"{total_height}\" font-family=\"Helvetica, Arial, sans-serif\" font-size=\"12\">"
+        );
+        let company_width = box_width(&company);
+        let company_x = (total_width - company_width) / 2;
+        draw_box(&mut svg, company_x, GAP, company_width, &company, true);
+        let company_bottom = GAP + box_height(1);
+        let mut x = GAP;
+        for ((head, posts), width) in columns.iter().zip(&widths) {
+            let center = x + width / 2;
+            let _ = writeln!(
+                svg,
+                "<path d=\"M{} {company_bottom} V{} H{center} V{department_y}\" fill=\"none\" stroke=\"#555\"/>",
+                total_width / 2,
+                company_bottom + GAP / 2
+            );
+            draw_box(&mut svg, x, department_y, *width, head, true);
+            let mut y = department_y + box_height(1);
+            for lines in posts {
+                let _ = writeln!(
+                    svg,
+                    "<line x1=\"{}\" y1=\"{y}\" x2=\"{}\" y2=\"{}\" stroke=\"#555\"/>",
+                    x + PADDING,
+                    x + PADDING,
+                    y + GAP / 2
+                );
+                y += GAP / 2;
+                draw_box(&mut svg, x, y, *width, lines, false);
+                y += box_height(lines.len());
+            }
+            x += width + GAP;
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+/// First line is the title, the rest are contacts.
+fn draw_box(svg: &mut String, x: usize, y: usize, width: usize, lines: &[String], bold: bool) {
+    let height = lines.len() * LINE_HEIGHT + PADDING;
+    let _ = writeln!(
+        svg,
+        "<rect x=\"{x}\" y=\"{y}\" width=\"{width}\" height=\"{height}\" rx=\"4\" fill=\"{}\" stroke=\"#555\"/>",
+        if bold { "#e8eef7" } else { "#ffffff" }
+    );
+    for (n, line) in lines.iter().enumerate() {
+        let _ = writeln!(
+            svg,
+            "<text x=\"{}\" y=\"{}\"{}>{}</text>",
+            x + PADDING,
+            y + (n + 1) * LINE_HEIGHT,
+            if n == 0 { " font-weight=\"bold\"" } else { "" },
+            escape_html(line)
+        );
+    }
+}
+
+pub async fn orgchart_cmd(
+    params: OrgChartParams,
+    pool: &RpelPool,
+) -> Result<Response<Body>, ServiceError> {
+    let chart = OrgChart::get(pool, params.company_id).await?;
+    let name = format!("orgchart_{}", params.company_id);
+    match params.format {
+        OrgChartFormat::Json => json_response(json!(chart)),
+        OrgChartFormat::Dot => file_response(
+            "text/vnd.graphviz; charset=utf-8",
+            &format!("{name}.dot"),
+            chart.to_dot().into_bytes(),
+        ),
+        OrgChartFormat::Svg => file_response(
+            "image/svg+xml",
+            &format!("{name}.svg"),
+            chart.to_svg().into_bytes(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contact(id: i64, name: &str, rank_id: Option<i64>, rank_name: &str) -> OrgContact {
+        OrgContact {
+            id,
+            name: Some(name.to_string()),
+            rank_id,
+            rank_name: Some(rank_name.to_string()),
+        }
+    }
+
+    #[test]
+    fn post_lists_contacts_by_rank_then_name() {
+        let mut post = OrgPost {
+            id: Some(1),
+            name: Some("Chief".to_string()),
+            contacts: vec![
+                contact(1, "Zed", None, ""),
+                contact(2, "Bob", Some(3), "Major"),
+                contact(3, "Amy", Some(1), "General"),
+                contact(4, "Al", Some(3), "Major"),
+            ],
+        };
+        post.sort_by_rank();
+        assert_eq!(
+            post.contacts.iter().map(|c| c.id).collect::<Vec<_>>(),
+            [3, 4, 2, 1]
+        );
+        assert_eq!(
+            post.lines(),
+            ["Chief", "Amy (General)", "Al (Major)", "Bob (Major)", "Zed"]
+        );
+    }
+
+    #[test]
+    fn empty_names_get_placeholders() {
+        let post = OrgPost {
+            id: None,
+            name: None,
+            contacts: Vec::new(),
+        };
+        assert_eq!(post.lines(), ["No post"]);
+    }
+}
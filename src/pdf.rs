@@ -0,0 +1,489 @@
+use std::fs::File;
+
+use chrono::NaiveDate;
+use hyper::{Body, Response};
+use printpdf::{
+    BuiltinFont, IndirectFontRef, Line, Mm, PdfDocument, PdfDocumentReference, PdfLayerReference,
+    Point,
+};
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::ServiceError, services::file_response};
+
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 20.0;
+const TITLE_SIZE: f32 = 16.0;
+const HEADING_SIZE: f32 = 13.0;
+const TEXT_SIZE: f32 = 10.0;
+const PT_TO_MM: f32 = 0.352_778;
+const LABEL_WIDTH: f32 = 50.0;
+
+#[derive(Deserialize, Serialize)]
+pub enum PdfObject {
+    Contact(i64),
+    Company(i64),
+    Practice(i64),
+}
+
+pub enum Block {
+    Fields(Vec<(String, String)>),
+    Table {
+        header: Vec<String>,
+        rows: Vec<Vec<String>>,
+    },
+    Text(String),
+}
+
+pub struct Section {
+    pub title: String,
+    pub blocks: Vec<Block>,
+}
+
+/// Printable document description, rendered to PDF by `Template::render`.
+pub struct Template {
+    pub title: String,
+    pub sections: Vec<Section>,
+}
+
+struct Writer {
+    doc: PdfDocumentReference,
+    font: IndirectFontRef,
+    bold: IndirectFontRef,
+    layer: PdfLayerReference,
+    y: f32,
+}
+
+impl Writer {
+    fn new(title: &str) -> Result<Writer, ServiceError> {
+        let (doc, page, layer) =
+            PdfDocument::new(title, Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Layer 1");
+        let regular = load_font(&doc, "RGO_PDF_FONT")?;
+        let bold = match (load_font(&doc, "RGO_PDF_FONT_BOLD")?, &regular) {
+            (Some(bold), _) => bold,
+            (None, Some(regular)) => regular.clone(),
+            (None, None) => doc.add_builtin_font(BuiltinFont::HelveticaBold)?,
+        };
+        let font = match regular {
+            Some(font) => font,
+            None => doc.add_builtin_font(BuiltinFont::Helvetica)?,
+        };
+        let layer = doc.get_page(page).get_layer(layer);
+        Ok(Writer {
+            doc,
+            font,
+            bold,
+            layer,
+            y: PAGE_HEIGHT - MARGIN,
+        })
+    }
+
+    fn line_height(size: f32) -> f32 {
+        size * PT_TO_MM * 1.4
+    }
+
+    fn ensure_space(&mut self, height: f32) {
+        if self.y - height < MARGIN {
+            let (page, layer) = self
+                .doc
+                .add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Layer 1");
+            self.layer = self.doc.get_page(page).get_layer(layer);
+            self.y = PAGE_HEIGHT - MARGIN;
+        }
+    }
+
+    fn text(&mut self, text: &str, size: f32, x: f32, width: f32, bold: bool) -> f32 {
+        let lines = wrap(text, size, width);
+        let height = Writer::line_height(size);
+        for line in &lines {
+            self.ensure_space(height);
+            self.y -= height;
+            let font = if bold { &self.bold } else { &self.font };
+            self.layer
+                .use_text(line.as_str(), size, Mm(x), Mm(self.y), font);
+        }
+        height * lines.len() as f32
+    }
+
+    fn rule(&mut self) {
+        self.y -= 1.5;
+        self.layer.add_line(Line {
+            points: vec![
+                (Point::new(Mm(MARGIN), Mm(self.y)), false),
+                (Point::new(Mm(PAGE_WIDTH - MARGIN), Mm(self.y)), false),
+            ],
+            is_closed: false,
+        });
+        self.y -= 1.5;
+    }
+
+    fn row(&mut self, cells: &[String], widths: &[f32], bold: bool) {
+        let height = Writer::line_height(TEXT_SIZE);
+        let wrapped: Vec<Vec<String>> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| wrap(cell, TEXT_SIZE, *width - 2.0))
+            .collect();
+        let lines = wrapped.iter().map(Vec::len).max().unwrap_or(1);
+        self.ensure_space(height * lines as f32);
+        let top = self.y;
+        let mut x = MARGIN;
+        for (cell, width) in wrapped.iter().zip(widths) {
+            let mut y = top;
+            for line in cell {
+                y -= height;
+                let font = if bold { &self.bold } else { &self.font };
+                self.layer
+                    .use_text(line.as_str(), TEXT_SIZE, Mm(x), Mm(y), font);
+            }
+            x += width;
+        }
+        self.y = top - height * lines as f32;
+    }
+
+    fn block(&mut self, block: &Block) {
+        let width = PAGE_WIDTH - 2.0 * MARGIN;
+        match block {
+            Block::Fields(fields) => {
+                for (label, value) in fields {
+                    let top = self.y;
+                    let label_height = self.text(label, TEXT_SIZE, MARGIN, LABEL_WIDTH, true);
+                    let bottom = self.y;
+                    self.y = top;
+                    let value_height = self.text(
+                        value,
+                        TEXT_SIZE,
+                        MARGIN + LABEL_WIDTH,
+                        width - LABEL_WIDTH,
+                        false,
+                    );
+                    if label_height > value_height {
+                        self.y = bottom;
+                    }
+                }
+            }
+            Block::Table { header, rows } => {
+                let columns = header.len().max(1);
+                let widths = vec![width / columns as f32; columns];
+                self.row(header, &widths, true);
+                self.rule();
+                for row in rows {
+                    self.row(row, &widths, false);
+                }
+            }
+            Block::Text(text) => {
+                self.text(text, TEXT_SIZE, MARGIN, width, false);
+            }
+        }
+    }
+}
+
+impl Template {
+    pub fn render(&self) -> Result<Vec<u8>, ServiceError> {
+        let mut writer = Writer::new(&self.title)?;
+        let width = PAGE_WIDTH - 2.0 * MARGIN;
+        writer.text(&self.title, TITLE_SIZE, MARGIN, width, true);
+        writer.rule();
+        for section in &self.sections {
+            writer.y -= Writer::line_height(TEXT_SIZE);
+            if !section.title.is_empty() {
+                writer.text(&section.title, HEADING_SIZE, MARGIN, width, true);
+            }
+            for block in &section.blocks {
+                writer.block(block);
+            }
+        }
+        Ok(writer.doc.save_to_bytes()?)
+    }
+}
+
+/// Loads the TTF file from `key`, builtin fonts do not cover Cyrillic.
+fn load_font(
+    doc: &PdfDocumentReference,
+    key: &str,
+) -> Result<Option<IndirectFontRef>, ServiceError> {
+    match dotenv::var(key) {
+        Ok(path) => Ok(Some(doc.add_external_font(File::open(path)?)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn wrap(text: &str, size: f32, width: f32) -> Vec<String> {
+    // Average glyph width is close to half of the font size.
+    let max_chars = ((width / (size * PT_TO_MM * 0.5)) as usize).max(1);
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let line_len = line.chars().count();
+            if line_len > 0 && line_len + 1 + word.chars().count() > max_chars {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+            while line.chars().count() > max_chars {
+                let head: String = line.chars().take(max_chars).collect();
+                line = line.chars().skip(max_chars).collect();
+                lines.push(head);
+            }
+        }
+        lines.push(line);
+    }
+    if lines.is_empty() {
+        lines.push(String::new());
+    }
+    lines
+}
+
+fn text(value: Option<String>) -> String {
+    value.unwrap_or_default()
+}
+
+fn date(value: Option<NaiveDate>) -> String {
+    value
+        .map(|date| date.format("%d.%m.%Y").to_string())
+        .unwrap_or_default()
+}
+
+fn join(values: Option<Vec<String>>) -> String {
+    values.unwrap_or_default().join(", ")
+}
+
+pub async fn contact_template(pool: &RpelPool, id: i64) -> Result<Template, ServiceError> {
+    let client = pool.get().await?;
+    let row = client
+        .query_one(
+            "SELECT
+                c.name,
+                co.name AS company_name,
+                d.name AS department_name,
+                p.name AS post_name,
+                pg.name AS post_go_name,
+                r.name AS rank_name,
+                c.birthday,
+                c.note,
+                ARRAY(SELECT email FROM emails WHERE contact_id = c.id ORDER BY email) AS emails,
+                ARRAY(SELECT phone::text FROM phones WHERE contact_id = c.id AND NOT fax ORDER BY phone) AS phones,
+                ARRAY(SELECT phone::text FROM phones WHERE contact_id = c.id AND fax ORDER BY phone) AS faxes
+            FROM
+                contacts AS c
+            LEFT JOIN
+                companies AS co ON co.id = c.company_id
+            LEFT JOIN
+                departments AS d ON d.id = c.department_id
+            LEFT JOIN
+                posts AS p ON p.id = c.post_id
+            LEFT JOIN
+                posts AS pg ON pg.id = c.post_go_id
+            LEFT JOIN
+                ranks AS r ON r.id = c.rank_id
+            WHERE
+                c.id = $1",
+            &[&id],
+        )
+        .await?;
+    let educations = client
+        .query(
+            "SELECT
+                e.start_date,
+                e.end_date,
+                p.name AS post_name
+            FROM
+                educations AS e
+            LEFT JOIN
+                posts AS p ON p.id = e.post_id
+            WHERE
+                e.contact_id = $1
+            ORDER BY
+                e.start_date DESC",
+            &[&id],
+        )
+        .await?;
+    let mut sections = vec![Section {
+        title: String::new(),
+        blocks: vec![Block::Fields(vec![
+            ("Company".to_string(), text(row.get("company_name"))),
+            ("Department".to_string(), text(row.get("department_name"))),
+            ("Post".to_string(), text(row.get("post_name"))),
+            (
+                "Civil defense post".to_string(),
+                text(row.get("post_go_name")),
+            ),
+            ("Rank".to_string(), text(row.get("rank_name"))),
+            ("Birthday".to_string(), date(row.get("birthday"))),
+            ("Phones".to_string(), join(row.get("phones"))),
+            ("Faxes".to_string(), join(row.get("faxes"))),
+            ("Emails".to_string(), join(row.get("emails"))),
+            ("Note".to_string(), text(row.get("note"))),
+        ])],
+    }];
+    if !educations.is_empty() {
+        sections.push(Section {
+            title: "Educations".to_string(),
+            blocks: vec![Block::Table {
+                header: vec!["Start".to_string(), "End".to_string(), "Post".to_string()],
+                rows: educations
+                    .iter()
+                    .map(|row| {
+                        vec![
+                            date(row.get("start_date")),
+                            date(row.get("end_date")),
+                            text(row.get("post_name")),
+                        ]
+                    })
+                    .collect(),
+            }],
+        });
+    }
+    Ok(Template {
+        title: text(row.get("name")),
+        sections,
+    })
+}
+
+pub async fn company_template(pool: &RpelPool, id: i64) -> Result<Template, ServiceError> {
+    let client = pool.get().await?;
+    let row = client
+        .query_one(
+            "SELECT
+                c.name,
+                c.address,
+                s.name AS scope_name,
+                c.note,
+                ARRAY(SELECT email FROM emails WHERE company_id = c.id ORDER BY email) AS emails,
+                ARRAY(SELECT phone::text FROM phones WHERE company_id = c.id AND NOT fax ORDER BY phone) AS phones,
+                ARRAY(SELECT phone::text FROM phones WHERE company_id = c.id AND fax ORDER BY phone) AS faxes
+            FROM
+                companies AS c
+            LEFT JOIN
+                scopes AS s ON s.id = c.scope_id
+            WHERE
+                c.id = $1",
+            &[&id],
+        )
+        .await?;
+    let contacts = client
+        .query(
+            "SELECT
+                c.name,
+                d.name AS department_name,
+                p.name AS post_name,
+                ARRAY(SELECT phone::text FROM phones WHERE contact_id = c.id AND NOT fax ORDER BY phone) AS phones
+            FROM
+                contacts AS c
+            LEFT JOIN
+                departments AS d ON d.id = c.department_id
+            LEFT JOIN
+                posts AS p ON p.id = c.post_id
+            WHERE
+                c.company_id = $1
+            ORDER BY
+                d.name NULLS FIRST, c.name",
+            &[&id],
+        )
+        .await?;
+    Ok(Template {
+        title: text(row.get("name")),
+        sections: vec![
+            Section {
+                title: String::new(),
+                blocks: vec![Block::Fields(vec![
+                    ("Address".to_string(), text(row.get("address"))),
+                    ("Scope".to_string(), text(row.get("scope_name"))),
+                    ("Phones".to_string(), join(row.get("phones"))),
+                    ("Faxes".to_string(), join(row.get("faxes"))),
+                    ("Emails".to_string(), join(row.get("emails"))),
+                    ("Note".to_string(), text(row.get("note"))),
+                ])],
+            },
+            Section {
+                title: "Contacts".to_string(),
+                blocks: vec![Block::Table {
+                    header: vec![
+                        "Name".to_string(),
+                        "Department".to_string(),
+                        "Post".to_string(),
+                        "Phones".to_string(),
+                    ],
+                    rows: contacts
+                        .iter()
+                        .map(|row| {
+                            vec![
+                                text(row.get("name")),
+                                text(row.get("department_name")),
+                                text(row.get("post_name")),
+                                join(row.get("phones")),
+                            ]
+                        })
+                        .collect(),
+                }],
+            },
+        ],
+    })
+}
+
+pub async fn practice_template(pool: &RpelPool, id: i64) -> Result<Template, ServiceError> {
+    let client = pool.get().await?;
+    let row = client
+        .query_one(
+            "SELECT
+                p.topic,
+                p.date_of_practice,
+                p.note,
+                c.name AS company_name,
+                c.address AS company_address,
+                k.name AS kind_name
+            FROM
+                practices AS p
+            LEFT JOIN
+                companies AS c ON c.id = p.company_id
+            LEFT JOIN
+                kinds AS k ON k.id = p.kind_id
+            WHERE
+                p.id = $1",
+            &[&id],
+        )
+        .await?;
+    Ok(Template {
+        title: format!("Practice protocol No {id}"),
+        sections: vec![
+            Section {
+                title: String::new(),
+                blocks: vec![Block::Fields(vec![
+                    ("Date".to_string(), date(row.get("date_of_practice"))),
+                    ("Company".to_string(), text(row.get("company_name"))),
+                    ("Address".to_string(), text(row.get("company_address"))),
+                    ("Kind".to_string(), text(row.get("kind_name"))),
+                    ("Topic".to_string(), text(row.get("topic"))),
+                ])],
+            },
+            Section {
+                title: "Notes".to_string(),
+                blocks: vec![Block::Text(text(row.get("note")))],
+            },
+            Section {
+                title: String::new(),
+                blocks: vec![Block::Fields(vec![(
+                    "Signature".to_string(),
+                    "______________________".to_string(),
+                )])],
+            },
+        ],
+    })
+}
+
+pub async fn pdf_cmd(obj: PdfObject, pool: &RpelPool) -> Result<Response<Body>, ServiceError> {
+    let (name, template) = match obj {
+        PdfObject::Contact(id) => (format!("contact_{id}"), contact_template(pool, id).await?),
+        PdfObject::Company(id) => (format!("company_{id}"), company_template(pool, id).await?),
+        PdfObject::Practice(id) => (format!("practice_{id}"), practice_template(pool, id).await?),
+    };
+    file_response(
+        "application/pdf",
+        &format!("{name}.pdf"),
+        template.render()?,
+    )
+}
@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use image::{imageops::FilterType, DynamicImage, ImageFormat, ImageOutputFormat};
+use rpel::RpelPool;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+
+use crate::{
+    attachment::{storage_dir, write_once},
+    config,
+    error::ServiceError,
+};
+
+const DEFAULT_FULL_SIZE: u32 = 800;
+const DEFAULT_THUMB_SIZE: u32 = 96;
+const JPEG_QUALITY: u8 = 85;
+
+#[derive(Clone, Copy)]
+pub enum PhotoSize {
+    Thumb,
+    Full,
+}
+
+impl PhotoSize {
+    pub fn parse(size: &str) -> Option<PhotoSize> {
+        match size {
+            "thumb" => Some(PhotoSize::Thumb),
+            "full" => Some(PhotoSize::Full),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            PhotoSize::Thumb => "thumb",
+            PhotoSize::Full => "full",
+        }
+    }
+}
+
+fn photo_path(hash: &str, size: PhotoSize) -> PathBuf {
+    storage_dir()
+        .join("photos")
+        .join(&hash[..2])
+        .join(format!("{hash}_{}.jpg", size.as_str()))
+}
+
+/// Versioned by hash, so a browser cache never shows an old photo.
+fn photo_url(contact_id: i64, hash: &str, size: PhotoSize) -> String {
+    format!("/go/photo/{contact_id}/{}?v={}", size.as_str(), &hash[..8])
+}
+
+fn encode(image: &DynamicImage) -> Result<Vec<u8>, ServiceError> {
+    let mut data = Cursor::new(Vec::new());
+    image
+        .to_rgb8()
+        .write_to(&mut data, ImageOutputFormat::Jpeg(JPEG_QUALITY))?;
+    Ok(data.into_inner())
+}
+
+fn read_u16(data: &[u8], at: usize, le: bool) -> Option<u16> {
+    let bytes = [*data.get(at)?, *data.get(at + 1)?];
+    Some(if le {
+        u16::from_le_bytes(bytes)
+    } else {
+        u16::from_be_bytes(bytes)
+    })
+}
+
+fn read_u32(data: &[u8], at: usize, le: bool) -> Option<u32> {
+    let bytes = [
+        *data.get(at)?,
+        *data.get(at + 1)?,
+        *data.get(at + 2)?,
+        *data.get(at + 3)?,
+    ];
+    Some(if le {
+        u32::from_le_bytes(bytes)
+    } else {
+        u32::from_be_bytes(bytes)
+    })
+}
+
+/// EXIF Orientation tag of the first IFD of a JPEG, `None` without one.
+fn exif_orientation(data: &[u8]) -> Option<u16> {
+    let mut at = 2;
+    if data.get(..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+    loop {
+        if *data.get(at)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(at + 1)?;
+        // Start of scan, the metadata segments are before it.
+        if marker == 0xDA {
+            return None;
+        }
+        let len = read_u16(data, at + 2, false)? as usize;
+        let segment = data.get(at + 4..at + 2 + len)?;
+        if marker == 0xE1 && segment.starts_with(b"Exif\0\0") {
+            let tiff = &segment[6..];
+            let le = match tiff.get(..2)? {
+                b"II" => true,
+                b"MM" => false,
+                _ => return None,
+            };
+            let ifd = read_u32(tiff, 4, le)? as usize;
+            let entries = read_u16(tiff, ifd, le)? as usize;
+            return (0..entries)
+                .map(|n| ifd + 2 + n * 12)
+                .find(|&entry| read_u16(tiff, entry, le) == Some(0x0112))
+                .and_then(|entry| read_u16(tiff, entry + 8, le));
+        }
+        at += 2 + len;
+    }
+}
+
+/// Turns the image as the camera meant it to be seen.
+fn orient(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Decodes and encodes again, so EXIF and any other metadata is dropped,
+/// the orientation of EXIF is applied before. Full keeps proportions, thumb
+/// is a centered square.
+fn resize(data: &[u8]) -> Result<(Vec<u8>, Vec<u8>), ServiceError> {
+    if !matches!(
+        image::guess_format(data)?,
+        ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Gif | ImageFormat::WebP
+    ) {
+        return Err(ServiceError::BadRequest(
+            "photo must be jpeg, png, gif or webp".to_string(),
+        ));
+    }
+    let image = image::load_from_memory(data)?;
+    let image = match exif_orientation(data) {
+        Some(orientation) => orient(image, orientation),
+        None => image,
+    };
+    let full_size = config::var_or("RGO_PHOTO_SIZE", DEFAULT_FULL_SIZE);
+    let thumb_size = config::var_or("RGO_PHOTO_THUMB_SIZE", DEFAULT_THUMB_SIZE);
+    let full = if image.width() > full_size || image.height() > full_size {
+        image.resize(full_size, full_size, FilterType::Lanczos3)
+    } else {
+        image.clone()
+    };
+    let thumb = image.resize_to_fill(thumb_size, thumb_size, FilterType::Lanczos3);
+    Ok((encode(&full)?, encode(&thumb)?))
+}
+
+pub async fn photo_hash(pool: &RpelPool, contact_id: i64) -> Result<Option<String>, ServiceError> {
+    let client = pool.get().await?;
+    Ok(client
+        .query_opt(
+            "SELECT hash FROM contact_photos WHERE contact_id = $1",
+            &[&contact_id],
+        )
+        .await?
+        .map(|row| row.get("hash")))
+}
+
+pub async fn store_photo(
+    pool: &RpelPool,
+    contact_id: i64,
+    data: Vec<u8>,
+) -> Result<String, ServiceError> {
+    let client = pool.get().await?;
+    client
+        .query_opt("SELECT id FROM contacts WHERE id = $1", &[&contact_id])
+        .await?
+        .ok_or_else(|| ServiceError::BadRequest(format!("no Contact {contact_id}")))?;
+    let (full, thumb) = tokio::task::spawn_blocking(move || resize(&data))
+        .await
+        .map_err(|err| ServiceError::Std(Box::new(err)))??;
+    let hash = format!("{:x}", Sha256::digest(&full));
+    write_once(photo_path(&hash, PhotoSize::Full), &full).await?;
+    write_once(photo_path(&hash, PhotoSize::Thumb), &thumb).await?;
+    let old = photo_hash(pool, contact_id).await?;
+    client
+        .execute(
+            "INSERT INTO contact_photos (contact_id, hash) VALUES ($1, $2)
+            ON CONFLICT (contact_id) DO UPDATE SET hash = $2, updated_at = now()",
+            &[&contact_id, &hash],
+        )
+        .await?;
+    if let Some(old) = old.filter(|old| *old != hash) {
+        remove_unused(pool, &old).await?;
+    }
+    Ok(photo_url(contact_id, &hash, PhotoSize::Full))
+}
+
+/// Deletes the files of a photo no contact refers to any more.
+pub async fn remove_unused(pool: &RpelPool, hash: &str) -> Result<(), ServiceError> {
+    let client = pool.get().await?;
+    let shared = client
+        .query_one(
+            "SELECT COUNT(*) AS count FROM contact_photos WHERE hash = $1",
+            &[&hash],
+        )
+        .await?
+        .get::<_, i64>("count");
+    if shared == 0 {
+        for size in [PhotoSize::Full, PhotoSize::Thumb] {
+            let path = photo_path(hash, size);
+            if fs::try_exists(&path).await? {
+                fs::remove_file(path).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn read_photo(
+    pool: &RpelPool,
+    contact_id: i64,
+    size: PhotoSize,
+) -> Result<Vec<u8>, ServiceError> {
+    let hash = photo_hash(pool, contact_id)
+        .await?
+        .ok_or_else(|| ServiceError::BadRequest(format!("contact {contact_id} has no photo")))?;
+    Ok(fs::read(photo_path(&hash, size)).await?)
+}
+
+fn set_urls(contact: &mut Value, photos: &HashMap<i64, String>) {
+    let Some(id) = contact.get("id").and_then(Value::as_i64) else {
+        return;
+    };
+    if let (Some(fields), Some(hash)) = (contact.as_object_mut(), photos.get(&id)) {
+        fields.insert(
+            "photo".to_string(),
+            Value::from(photo_url(id, hash, PhotoSize::Full)),
+        );
+        fields.insert(
+            "photo_thumb".to_string(),
+            Value::from(photo_url(id, hash, PhotoSize::Thumb)),
+        );
+    }
+}
+
+/// Adds `photo` and `photo_thumb` urls to a serialized `Contact` or
+/// `ContactList` message, other objects are left as is.
+pub async fn with_photo_urls(pool: &RpelPool, msg: &mut Value) -> Result<(), ServiceError> {
+    let Some(object) = msg.get_mut("object").and_then(Value::as_object_mut) else {
+        return Ok(());
+    };
+    let contacts: Vec<&mut Value> = if object.contains_key("Contact") {
+        object.get_mut("Contact").into_iter().collect()
+    } else if let Some(Value::Array(list)) = object.get_mut("ContactList") {
+        list.iter_mut().collect()
+    } else {
+        return Ok(());
+    };
+    let ids: Vec<i64> = contacts
+        .iter()
+        .filter_map(|contact| contact.get("id").and_then(Value::as_i64))
+        .collect();
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let client = pool.get().await?;
+    let photos: HashMap<i64, String> = client
+        .query(
+            "SELECT contact_id, hash FROM contact_photos WHERE contact_id = ANY($1)",
+            &[&ids],
+        )
+        .await?
+        .iter()
+        .map(|row| (row.get("contact_id"), row.get("hash")))
+        .collect();
+    for contact in contacts {
+        set_urls(contact, &photos);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jpeg_with_orientation(tiff: &[u8]) -> Vec<u8> {
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend_from_slice(tiff);
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00, 0xFF, 0xE1];
+        data.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+        data.extend_from_slice(&app1);
+        data.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02]);
+        data
+    }
+
+    #[test]
+    fn reads_orientation_of_both_byte_orders() {
+        let little = [
+            b'I', b'I', 42, 0, 8, 0, 0, 0, 1, 0, 0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0,
+        ];
+        let big = [
+            b'M', b'M', 0, 42, 0, 0, 0, 8, 0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 8, 0, 0,
+        ];
+        assert_eq!(exif_orientation(&jpeg_with_orientation(&little)), Some(6));
+        assert_eq!(exif_orientation(&jpeg_with_orientation(&big)), Some(8));
+    }
+
+    #[test]
+    fn no_orientation_without_exif() {
+        assert_eq!(
+            exif_orientation(&[0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x02]),
+            None
+        );
+        assert_eq!(exif_orientation(b"\x89PNG"), None);
+    }
+
+    #[test]
+    fn rotates_sideways_photos() {
+        let image = DynamicImage::new_rgb8(4, 2);
+        assert_eq!(orient(image.clone(), 6).width(), 2);
+        assert_eq!(orient(image.clone(), 3).width(), 4);
+        assert_eq!(orient(image, 8).height(), 4);
+    }
+}
@@ -0,0 +1,373 @@
+use std::fmt::Write;
+
+use chrono::NaiveDate;
+use hyper::{Body, Response};
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    error::ServiceError,
+    pdf::{Block, Section, Template},
+    services::{file_response, json_response},
+};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Csv,
+    Html,
+    Pdf,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReportParams {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    #[serde(default)]
+    pub format: ReportFormat,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EducationRow {
+    pub company_id: i64,
+    pub company_name: Option<String>,
+    pub scope_name: Option<String>,
+    pub department_id: Option<i64>,
+    pub department_name: Option<String>,
+    pub employees: i64,
+    pub educated: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PracticeRow {
+    pub company_id: i64,
+    pub company_name: Option<String>,
+    pub scope_name: Option<String>,
+    pub kind_id: Option<i64>,
+    pub kind_name: Option<String>,
+    pub practices: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub educations: Vec<EducationRow>,
+    pub practices: Vec<PracticeRow>,
+}
+
+impl Report {
+    pub async fn get(
+        pool: &RpelPool,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Report, ServiceError> {
+        if from > to {
+            return Err(ServiceError::BadRequest(format!(
+                "bad report range: {from} - {to}"
+            )));
+        }
+        let client = pool.get().await?;
+        let educations = client
+            .query(
+                "SELECT
+                    c.id AS company_id,
+                    c.name AS company_name,
+                    s.name AS scope_name,
+                    d.id AS department_id,
+                    d.name AS department_name,
+                    COUNT(DISTINCT ct.id) AS employees,
+                    COUNT(DISTINCT e.contact_id) AS educated
+                FROM
+                    contacts AS ct
+                JOIN
+                    companies AS c ON c.id = ct.company_id
+                LEFT JOIN
+                    scopes AS s ON s.id = c.scope_id
+                LEFT JOIN
+                    departments AS d ON d.id = ct.department_id
+                LEFT JOIN
+                    educations AS e ON e.contact_id = ct.id AND e.end_date BETWEEN $1 AND $2
+                GROUP BY
+                    c.id, c.name, s.name, d.id, d.name
+                ORDER BY
+                    c.name, d.name NULLS FIRST",
+                &[&from, &to],
+            )
+            .await?
+            .iter()
+            .map(|row| EducationRow {
+                company_id: row.get("company_id"),
+                company_name: row.get("company_name"),
+                scope_name: row.get("scope_name"),
+                department_id: row.get("department_id"),
+                department_name: row.get("department_name"),
+                employees: row.get("employees"),
+                educated: row.get("educated"),
+            })
+            .collect();
+        let practices = client
+            .query(
+                "SELECT
+                    c.id AS company_id,
+                    c.name AS company_name,
+                    s.name AS scope_name,
+                    k.id AS kind_id,
+                    k.name AS kind_name,
+                    COUNT(p.id) AS practices
+                FROM
+                    practices AS p
+                JOIN
+                    companies AS c ON c.id = p.company_id
+                LEFT JOIN
+                    scopes AS s ON s.id = c.scope_id
+                LEFT JOIN
+                    kinds AS k ON k.id = p.kind_id
+                WHERE
+                    p.date_of_practice BETWEEN $1 AND $2
+                GROUP BY
+                    c.id, c.name, s.name, k.id, k.name
+                ORDER BY
+                    c.name, k.name NULLS FIRST",
+                &[&from, &to],
+            )
+            .await?
+            .iter()
+            .map(|row| PracticeRow {
+                company_id: row.get("company_id"),
+                company_name: row.get("company_name"),
+                scope_name: row.get("scope_name"),
+                kind_id: row.get("kind_id"),
+                kind_name: row.get("kind_name"),
+                practices: row.get("practices"),
+            })
+            .collect();
+        Ok(Report {
+            from,
+            to,
+            educations,
+            practices,
+        })
+    }
+
+    fn education_table(&self) -> (Vec<&str>, Vec<Vec<String>>) {
+        (
+            vec!["Company", "Scope", "Department", "Employees", "Educated"],
+            self.educations
+                .iter()
+                .map(|row| {
+                    vec![
+                        text(&row.company_name),
+                        text(&row.scope_name),
+                        text(&row.department_name),
+                        row.employees.to_string(),
+                        row.educated.to_string(),
+                    ]
+                })
+                .collect(),
+        )
+    }
+
+    fn practice_table(&self) -> (Vec<&str>, Vec<Vec<String>>) {
+        (
+            vec!["Company", "Scope", "Kind", "Practices"],
+            self.practices
+                .iter()
+                .map(|row| {
+                    vec![
+                        text(&row.company_name),
+                        text(&row.scope_name),
+                        text(&row.kind_name),
+                        row.practices.to_string(),
+                    ]
+                })
+                .collect(),
+        )
+    }
+
+    pub fn to_csv(&self) -> String {
+        // BOM lets spreadsheet applications detect UTF-8 in company names.
+        let mut csv = String::from('\u{feff}');
+        for (title, (header, rows)) in [
+            ("Educations", self.education_table()),
+            ("Practices", self.practice_table()),
+        ] {
+            csv_line(
+                &mut csv,
+                [format!("{title} {} - {}", self.from, self.to)].into_iter(),
+            );
+            csv_line(&mut csv, header.iter().map(|h| h.to_string()));
+            for row in rows {
+                csv_line(&mut csv, row.into_iter());
+            }
+            csv.push_str("\r\n");
+        }
+        csv
+    }
+
+    pub fn to_template(&self) -> Template {
+        let sections = [
+            ("Educations", self.education_table()),
+            ("Practices", self.practice_table()),
+        ]
+        .into_iter()
+        .map(|(title, (header, rows))| Section {
+            title: title.to_string(),
+            blocks: vec![Block::Table {
+                header: header.iter().map(|h| h.to_string()).collect(),
+                rows,
+            }],
+        })
+        .collect();
+        Template {
+            title: format!("Report {} - {}", self.from, self.to),
+            sections,
+        }
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut html = format!(
+            "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Report {from} - {to}</title>
+<style>
+body {{ font-family: sans-serif; font-size: 12pt; margin: 2em; }}
+table {{ border-collapse: collapse; width: 100%; margin-bottom: 2em; }}
+th, td {{ border: 1px solid #444; padding: 4px 8px; text-align: left; }}
+th {{ background: #eee; }}
+@media print {{ body {{ margin: 0; }} h2 {{ page-break-before: auto; }} tr {{ page-break-inside: avoid; }} }}
+</style>
+</head>
+<body>
+<h1>Report {from} - {to}</h1>
+",
+            from = self.from,
+            to = self.to
+        );
+        for (title, (header, rows)) in [
+            ("Educations", self.education_table()),
+            ("Practices", self.practice_table()),
+        ] {
+            let _ = writeln!(html, "<h2>{title}</h2>\n<table>\n<tr>");
+            for cell in header {
+                let _ = write!(html, "<th>{}</th>", escape_html(cell));
+            }
+            html.push_str("</tr>\n");
+            for row in rows {
+                html.push_str("<tr>");
+                for cell in row {
+                    let _ = write!(html, "<td>{}</td>", escape_html(&cell));
+                }
+                html.push_str("</tr>\n");
+            }
+            html.push_str("</table>\n");
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+fn text(value: &Option<String>) -> String {
+    value.clone().unwrap_or_default()
+}
+
+/// Appends one CRLF terminated record. Cells that a spreadsheet would
+/// evaluate as a formula get a leading `'`.
+fn csv_line(csv: &mut String, cells: impl Iterator<Item = String>) {
+    let line: Vec<String> = cells
+        .map(|cell| {
+            if cell.starts_with(['=', '+', '-', '@']) {
+                format!("'{cell}")
+            } else {
+                cell
+            }
+        })
+        .map(|cell| {
+            if cell.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", cell.replace('"', "\"\""))
+            } else {
+                cell
+            }
+        })
+        .collect();
+    csv.push_str(&line.join(","));
+    csv.push_str("\r\n");
+}
+
+pub fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub async fn report_cmd(
+    params: ReportParams,
+    pool: &RpelPool,
+) -> Result<Response<Body>, ServiceError> {
+    let report = Report::get(pool, params.from, params.to).await?;
+    let name = format!("report_{}_{}", params.from, params.to);
+    match params.format {
+        ReportFormat::Json => json_response(json!(report)),
+        ReportFormat::Csv => file_response(
+            "text/csv; charset=utf-8",
+            &format!("{name}.csv"),
+            report.to_csv().into_bytes(),
+        ),
+        ReportFormat::Html => file_response(
+            "text/html; charset=utf-8",
+            &format!("{name}.html"),
+            report.to_html().into_bytes(),
+        ),
+        ReportFormat::Pdf => file_response(
+            "application/pdf",
+            &format!("{name}.pdf"),
+            report.to_template().render()?,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(cells: &[&str]) -> String {
+        let mut csv = String::new();
+        csv_line(&mut csv, cells.iter().map(|cell| cell.to_string()));
+        csv
+    }
+
+    #[test]
+    fn csv_lines_end_with_crlf() {
+        assert_eq!(line(&["a", "b"]), "a,b\r\n");
+    }
+
+    #[test]
+    fn csv_quotes_special_characters() {
+        assert_eq!(
+            line(&["a,b", "say \"hi\"", "x\ny"]),
+            "\"a,b\",\"say \"\"hi\"\"\",\"x\ny\"\r\n"
+        );
+    }
+
+    #[test]
+    fn csv_neutralizes_formulas() {
+        assert_eq!(
+            line(&["=1+2", "+7", "-3", "@SUM(A1)", "a=b"]),
+            "'=1+2,'+7,'-3,'@SUM(A1),a=b\r\n"
+        );
+        assert_eq!(line(&["=A1,B1"]), "\"'=A1,B1\"\r\n");
+    }
+}
@@ -4,53 +4,294 @@ use hyper::{
     Body, Request, Response,
 };
 use log::debug;
-use routerify::ext::RequestExt;
+use multer::{Constraints, Multipart, SizeLimit};
+use routerify::{ext::RequestExt, RequestInfo};
 use serde_json::{from_slice, json, Value};
 
 use crate::{
-    auth::{check, C},
-    dbo::{delete_item, get_item, get_list, insert_item, update_item, DbObject},
-    messages::{ClientMessage, Command, WsMsg},
+    access::Access,
+    apikey::api_key_user,
+    attachment::{self, attachment_cmd, Attachment, Upload},
+    auth::{audit, check, C},
+    dashboard::Dashboard,
+    dbo::{delete_item, get_item, insert_item, update_item, DbObject},
+    duplicate::{merge_contacts, ContactDuplicate},
+    history::{get_history, get_item_at, revert_item},
+    messages::{ClientMessage, Command, Item, WsMsg},
+    orgchart::orgchart_cmd,
+    pdf::pdf_cmd,
+    photo::{read_photo, store_photo, with_photo_urls, PhotoSize},
+    report::report_cmd,
+    session::SessionInfo,
+    totp::{is_enabled, totp_cmd, TotpLogin, TotpRequired},
 };
 use crate::{
-    auth::{Auth, A},
+    auth::{authenticate, Auth, Refresh, A},
+    jwt::{issue_tokens, refresh_tokens, revoke_refresh_token},
     State,
 };
-use crate::{error::ServiceError, users::user_cmd};
+use crate::{error::ServiceError, geo::geo_cmd, users::user_cmd};
+
+/// Command part of a client message, without the token.
+fn command_json(body: &[u8]) -> String {
+    from_slice::<Value>(body)
+        .ok()
+        .and_then(|mut value| value.get_mut("command").map(Value::take))
+        .unwrap_or_default()
+        .to_string()
+}
 
 pub async fn jsonpost(req: Request<Body>) -> Result<Response<Body>, ServiceError> {
     let state = req.data::<State>().ok_or(ServiceError::NoState)?;
     let users = state.users.clone();
     let pool = &state.pool.clone();
-    let params: ClientMessage = from_slice(dbg!(&to_bytes(req).await?))?;
-    let cmd = check(&users, params)?;
+    let guard = state.guard.clone();
+    let api_user = api_key_user(pool, req.headers()).await?;
+    let info = SessionInfo::from_request(&req);
+    let body = to_bytes(req).await?;
+    let params: ClientMessage = from_slice(&body)?;
+    let (user, cmd) = check(&users, params, api_user)?;
+    audit(&user, || command_json(&body));
+    let access = Access::from(&user);
+    access.check_command(pool, &cmd).await?;
     let msg = match cmd {
         Command::GetItem(item) => {
             WsMsg::from_dbo("GetItem", item.name.clone(), get_item(&item, pool).await)
         }
         Command::GetList(list) => {
-            WsMsg::from_dbo("GetList", list.clone(), get_list(&list, pool).await)
+            WsMsg::from_dbo("GetList", list.clone(), access.get_list(&list, pool).await)
         }
         Command::InsertItem(dbobject) => WsMsg::from_dbo(
             "InsertItem",
             dbobject.name(),
-            Ok(insert_item(dbobject, pool).await.map(|_| DbObject::Null)?),
+            insert_item(dbobject, pool).await.map(|_| DbObject::Null),
         ),
         Command::UpdateItem(dbobject) => WsMsg::from_dbo(
             "UpdateItem",
             dbobject.name(),
-            Ok(update_item(dbobject, pool).await.map(|_| DbObject::Null)?),
+            update_item(dbobject, pool).await.map(|_| DbObject::Null),
         ),
         Command::DeleteItem(item) => WsMsg::from_dbo(
             "DeleteItem",
             item.name.clone(),
             Ok(delete_item(&item, pool).await.map(|_| DbObject::Null)?),
         ),
-        Command::User(obj) => return user_cmd(obj, pool).await,
+        Command::Dashboard => WsMsg::from_dbo(
+            "Dashboard",
+            String::from("Dashboard"),
+            Dashboard::get(pool)
+                .await
+                .map(|dashboard| DbObject::Dashboard(Box::new(dashboard))),
+        ),
+        Command::FindDuplicates(threshold) => WsMsg::from_dbo(
+            "FindDuplicates",
+            String::from("ContactDuplicate"),
+            ContactDuplicate::get_all(pool, threshold)
+                .await
+                .map(DbObject::ContactDuplicate),
+        ),
+        Command::MergeContacts { keep, remove } => WsMsg::from_dbo(
+            "MergeContacts",
+            String::from("Contact"),
+            merge_contacts(pool, keep, remove)
+                .await
+                .map(|_| DbObject::Null),
+        ),
+        Command::GetHistory(item) => WsMsg::from_dbo(
+            "GetHistory",
+            item.name.clone(),
+            get_history(pool, &item)
+                .await
+                .map(DbObject::EntityVersionList),
+        ),
+        Command::GetItemAt(item, at) => WsMsg::from_dbo(
+            "GetItemAt",
+            item.name.clone(),
+            get_item_at(pool, &item, at).await,
+        ),
+        Command::RevertItem(item, version) => WsMsg::from_dbo(
+            "RevertItem",
+            item.name.clone(),
+            revert_item(pool, &item, version)
+                .await
+                .map(|_| DbObject::Null),
+        ),
+        Command::User(obj) => return user_cmd(obj, &user, &users, pool, &guard, &info).await,
+        Command::Totp(obj) => return totp_cmd(obj, &user, pool).await,
+        Command::Geo(obj) => return geo_cmd(obj, pool).await,
+        Command::Report(params) => return report_cmd(params, pool).await,
+        Command::Pdf(obj) => return pdf_cmd(obj, pool).await,
+        Command::OrgChart(params) => return orgchart_cmd(params, pool).await,
+        Command::Attachment(obj) => return attachment_cmd(obj, pool).await,
     };
+    let mut value = json!(msg);
+    with_photo_urls(pool, &mut value).await?;
+    json_response(value)
+}
+
+pub async fn download(req: Request<Body>) -> Result<Response<Body>, ServiceError> {
+    let state = req.data::<State>().ok_or(ServiceError::NoState)?;
+    let users = state.users.clone();
+    let pool = &state.pool.clone();
+    let api_user = api_key_user(pool, req.headers()).await?;
+    let body = to_bytes(req).await?;
+    let params: ClientMessage = from_slice(&body)?;
+    let (user, cmd) = check(&users, params, api_user)?;
+    audit(&user, || command_json(&body));
+    Access::from(&user).check_command(pool, &cmd).await?;
+    match cmd {
+        Command::Report(params) => report_cmd(params, pool).await,
+        Command::Pdf(obj) => pdf_cmd(obj, pool).await,
+        Command::OrgChart(params) => orgchart_cmd(params, pool).await,
+        Command::Attachment(obj) => attachment_cmd(obj, pool).await,
+        _ => Err(ServiceError::BadRequest(
+            "command has no downloadable result".to_string(),
+        )),
+    }
+}
+
+/// Reads a multipart form with `addon` token, `entity`, `entity_id` and
+/// `file` fields.
+async fn read_upload(req: Request<Body>) -> Result<(String, Upload), ServiceError> {
+    let boundary = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| multer::parse_boundary(value).ok())
+        .ok_or_else(|| ServiceError::BadRequest("no multipart boundary".to_string()))?;
+    let limit = attachment::max_size() as u64;
+    let constraints = Constraints::new().size_limit(
+        SizeLimit::new()
+            .per_field(limit)
+            .whole_stream(limit + 64 * 1024),
+    );
+    let mut multipart = Multipart::with_constraints(req.into_body(), boundary, constraints);
+    let mut addon = String::new();
+    let mut upload = Upload {
+        entity: String::new(),
+        entity_id: 0,
+        filename: String::new(),
+        data: Vec::new(),
+    };
+    while let Some(field) = multipart.next_field().await? {
+        match field.name().unwrap_or_default() {
+            "addon" => addon = field.text().await?,
+            "entity" => upload.entity = field.text().await?,
+            "entity_id" => upload.entity_id = field.text().await?.trim().parse().unwrap_or(0),
+            "file" => {
+                upload.filename = field.file_name().unwrap_or_default().to_string();
+                upload.data = field.bytes().await?.to_vec();
+            }
+            _ => {}
+        }
+    }
+    Ok((addon, upload))
+}
+
+pub async fn upload(req: Request<Body>) -> Result<Response<Body>, ServiceError> {
+    let state = req.data::<State>().ok_or(ServiceError::NoState)?;
+    let users = state.users.clone();
+    let pool = &state.pool.clone();
+    let api_user = api_key_user(pool, req.headers()).await?;
+    let (addon, upload) = read_upload(req).await?;
+    let user = api_user
+        .or_else(|| users.get_user(&addon))
+        .ok_or(ServiceError::NotAuth)?;
+    // uploading needs the same right as InsertItem
+    if user.role >> 3 == 0 {
+        return Err(ServiceError::NotPermission);
+    }
+    audit(&user, || {
+        format!("upload to {} {}", upload.entity, upload.entity_id)
+    });
+    Access::from(&user)
+        .check_entity(pool, &upload.entity, upload.entity_id)
+        .await?;
+    let msg = WsMsg::from_dbo(
+        "UploadAttachment",
+        String::from("Attachment"),
+        Attachment::store(pool, upload, user.id)
+            .await
+            .map(|attachment| DbObject::Attachment(Box::new(attachment))),
+    );
     json_response(json!(msg))
 }
 
+/// Same form as `upload`, `entity_id` is the contact id.
+pub async fn upload_photo(req: Request<Body>) -> Result<Response<Body>, ServiceError> {
+    let state = req.data::<State>().ok_or(ServiceError::NoState)?;
+    let users = state.users.clone();
+    let pool = &state.pool.clone();
+    let api_user = api_key_user(pool, req.headers()).await?;
+    let (addon, upload) = read_upload(req).await?;
+    let user = api_user
+        .or_else(|| users.get_user(&addon))
+        .ok_or(ServiceError::NotAuth)?;
+    // a new photo changes the contact, so it needs the UpdateItem right
+    if user.role >> 4 == 0 {
+        return Err(ServiceError::NotPermission);
+    }
+    audit(&user, || format!("photo of Contact {}", upload.entity_id));
+    Access::from(&user)
+        .check_entity(pool, "Contact", upload.entity_id)
+        .await?;
+    let url = store_photo(pool, upload.entity_id, upload.data).await?;
+    json_response(json!({
+        "command": "UploadPhoto",
+        "name": "Contact",
+        "photo": url,
+        "error": "",
+    }))
+}
+
+/// Token from `Authorization: Bearer` or the `t` query parameter, the
+/// latter lets photos be used directly in `img` tags.
+fn request_token(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            req.uri()
+                .query()?
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("t="))
+        })
+        .map(String::from)
+}
+
+pub async fn photo(req: Request<Body>) -> Result<Response<Body>, ServiceError> {
+    let state = req.data::<State>().ok_or(ServiceError::NoState)?;
+    let users = state.users.clone();
+    let pool = &state.pool.clone();
+    let id: i64 = req
+        .param("id")
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| ServiceError::BadRequest("bad contact id".to_string()))?;
+    let size = req
+        .param("size")
+        .and_then(|size| PhotoSize::parse(size))
+        .ok_or_else(|| ServiceError::BadRequest("bad photo size".to_string()))?;
+    let user = request_token(&req)
+        .and_then(|token| users.get_user(&token))
+        .ok_or(ServiceError::NotAuth)?;
+    let command = user.permissions(Command::GetItem(Item {
+        name: String::from("Contact"),
+        id,
+    }))?;
+    Access::from(&user).check_command(pool, &command).await?;
+    let mut res = file_response(
+        "image/jpeg",
+        &format!("contact_{id}.jpg"),
+        read_photo(pool, id, size).await?,
+    )?;
+    res.headers_mut().insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, max-age=31536000"),
+    );
+    Ok(res)
+}
+
 pub fn json_response(body: Value) -> Result<Response<Body>, ServiceError> {
     Ok(Response::builder()
         .header("Content-Type", "application/json")
@@ -58,35 +299,39 @@ pub fn json_response(body: Value) -> Result<Response<Body>, ServiceError> {
         .body(Body::from(body.to_string()))?)
 }
 
-// pub fn enable_cors_all() -> Middleware<Body, ServiceError> {
-//     Middleware::post(enable_cors_all_middleware_handler)
-// }
+pub fn file_response(
+    content_type: &str,
+    filename: &str,
+    body: Vec<u8>,
+) -> Result<Response<Body>, ServiceError> {
+    Ok(Response::builder()
+        .header("Content-Type", content_type)
+        .header(
+            "Content-Disposition",
+            format!("inline; filename=\"{filename}\""),
+        )
+        .status(200)
+        .body(Body::from(body))?)
+}
 
-pub async fn enable_cors_all_middleware_handler(
+/// Adds the headers of the CORS policy to every response.
+pub async fn cors_middleware_handler(
     mut res: Response<Body>,
+    req_info: RequestInfo,
 ) -> Result<Response<Body>, ServiceError> {
-    let headers = res.headers_mut();
-
-    headers.insert(
-        header::ACCESS_CONTROL_ALLOW_ORIGIN,
-        HeaderValue::from_static("*"),
-        // HeaderValue::from_static("http://localhost:3000"),
-    );
-    // HeaderValue::from_static("http://localhost:3000"),
-    // http://localhost:3000, chrome-extension://bnmefgocpeggmnpkglmkfoidibbcogcf, moz-extension://4b800887-ba22-4cb5-a284-41421b565e0e
-    headers.insert(
-        header::ACCESS_CONTROL_ALLOW_METHODS,
-        HeaderValue::from_static("POST, OPTIONS"),
-    );
-    headers.insert(
-        header::ACCESS_CONTROL_ALLOW_HEADERS,
-        HeaderValue::from_static("*"),
-    );
-    // debug!("{:?}", headers);
-
+    let state = req_info.data::<State>().ok_or(ServiceError::NoState)?;
+    state
+        .cors
+        .apply(res.headers_mut(), req_info.headers(), req_info.method());
     Ok(res)
 }
 
+/// Answers CORS preflight requests of every route, the headers are added by
+/// `cors_middleware_handler`.
+pub async fn preflight(_req: Request<Body>) -> Result<Response<Body>, ServiceError> {
+    Ok(Response::builder().status(204).body(Body::empty())?)
+}
+
 pub async fn logger(req: Request<Body>) -> Result<Request<Body>, ServiceError> {
     debug!(
         "{} {} {}",
@@ -104,23 +349,121 @@ pub async fn check_auth(req: Request<Body>) -> Result<Response<Body>, ServiceErr
         .users
         .clone();
     let params: A = serde_json::from_slice(&to_bytes(req).await?)?;
-    let result = users
-        .get_user(&params.t)
-        .map(|u| u.role == params.r)
-        .map_or(false, |v| v);
-    json_response(json!(&C { r: result }))
+    let user = users.get_user(&params.t);
+    let result = matches!(&user, Some(u) if u.role == params.r);
+    json_response(json!(&C {
+        r: result,
+        imp: user.and_then(|u| u.impersonator),
+    }))
 }
 
 pub async fn login(req: Request<Body>) -> Result<Response<Body>, ServiceError> {
-    let users = req
-        .data::<State>()
-        .ok_or(ServiceError::NoState)?
-        .users
-        .clone();
-    let params: Auth = serde_json::from_slice(dbg!(&to_bytes(req).await?))?;
-    let reply = dbg!(users.get_reply(&params.u, &params.p)).ok_or(ServiceError::NotAuth)?;
-    json_response(json!(&A {
-        t: reply.0,
-        r: reply.1,
+    let state = req.data::<State>().ok_or(ServiceError::NoState)?;
+    let users = state.users.clone();
+    let pool = &state.pool.clone();
+    let totp = state.totp.clone();
+    let guard = state.guard.clone();
+    let backends = state.auth.clone();
+    let ip = req.remote_addr().ip();
+    let info = SessionInfo::from_request(&req);
+    let params: Auth = serde_json::from_slice(&to_bytes(req).await?)?;
+    guard.check(ip, &params.u)?;
+    let Some(user) = authenticate(&backends, pool, &params.u, &params.p).await else {
+        guard.failure(ip, &params.u);
+        return Err(ServiceError::NotAuth);
+    };
+    guard.success(&params.u);
+    if is_enabled(pool, &user).await? {
+        return json_response(json!(&TotpRequired {
+            totp: totp.insert(user),
+        }));
+    }
+    json_response(json!(&issue_tokens(pool, &users, &user, &info).await?))
+}
+
+pub async fn login_totp(req: Request<Body>) -> Result<Response<Body>, ServiceError> {
+    let state = req.data::<State>().ok_or(ServiceError::NoState)?;
+    let users = state.users.clone();
+    let pool = &state.pool.clone();
+    let totp = state.totp.clone();
+    let guard = state.guard.clone();
+    let ip = req.remote_addr().ip();
+    let info = SessionInfo::from_request(&req);
+    let params: TotpLogin = serde_json::from_slice(&to_bytes(req).await?)?;
+    let name = totp.name(&params.totp).unwrap_or_default();
+    guard.check(ip, &name)?;
+    let user = match totp.complete(pool, &params).await {
+        Ok(user) => user,
+        Err(err) => {
+            guard.failure(ip, &name);
+            return Err(err);
+        }
+    };
+    guard.success(&name);
+    json_response(json!(&issue_tokens(pool, &users, &user, &info).await?))
+}
+
+pub async fn refresh(req: Request<Body>) -> Result<Response<Body>, ServiceError> {
+    let state = req.data::<State>().ok_or(ServiceError::NoState)?;
+    let users = state.users.clone();
+    let pool = &state.pool.clone();
+    let info = SessionInfo::from_request(&req);
+    let params: Refresh = serde_json::from_slice(&to_bytes(req).await?)?;
+    json_response(json!(
+        &refresh_tokens(pool, &users, &params.f, &info).await?
+    ))
+}
+
+/// Ends the session of the refresh token.
+pub async fn logout(req: Request<Body>) -> Result<Response<Body>, ServiceError> {
+    let state = req.data::<State>().ok_or(ServiceError::NoState)?;
+    let users = state.users.clone();
+    let pool = &state.pool.clone();
+    let params: Refresh = serde_json::from_slice(&to_bytes(req).await?)?;
+    json_response(json!(&C {
+        r: revoke_refresh_token(pool, &users, &params.f).await? > 0,
+        imp: None,
     }))
 }
+
+fn redirect(location: &str) -> Result<Response<Body>, ServiceError> {
+    Ok(Response::builder()
+        .header(header::LOCATION, location)
+        .status(302)
+        .body(Body::empty())?)
+}
+
+pub async fn oidc_login(req: Request<Body>) -> Result<Response<Body>, ServiceError> {
+    let state = req.data::<State>().ok_or(ServiceError::NoState)?;
+    redirect(&state.oidc.login_url().await?)
+}
+
+/// Return of the OIDC provider. The token goes to the fragment of
+/// `RGO_OIDC_FRONTEND_URL` when it is set, otherwise it is the reply like
+/// the one of `/go/login`.
+pub async fn oidc_callback(req: Request<Body>) -> Result<Response<Body>, ServiceError> {
+    let state = req.data::<State>().ok_or(ServiceError::NoState)?;
+    let users = state.users.clone();
+    let pool = &state.pool.clone();
+    let totp = state.totp.clone();
+    let info = SessionInfo::from_request(&req);
+    let query = req.uri().query().unwrap_or_default();
+    let user = state.oidc.callback(pool, query).await?;
+    let (fragment, body) = if is_enabled(pool, &user).await? {
+        let pending = totp.insert(user);
+        (
+            format!("totp={pending}"),
+            json!(&TotpRequired { totp: pending }),
+        )
+    } else {
+        let tokens = issue_tokens(pool, &users, &user, &info).await?;
+        (
+            format!("t={}&r={}&f={}", tokens.t, tokens.r, tokens.f),
+            json!(&tokens),
+        )
+    };
+    match dotenv::var("RGO_OIDC_FRONTEND_URL") {
+        Ok(url) if !url.is_empty() => redirect(&format!("{url}#{fragment}")),
+        _ => json_response(body),
+    }
+}
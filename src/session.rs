@@ -0,0 +1,267 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use chrono::{Local, NaiveDateTime};
+use hyper::{header, Body, Request};
+use routerify::ext::RequestExt;
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::types::ToSql;
+
+use crate::error::ServiceError;
+
+/// A login of a user, alive while it has a valid refresh token.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Session {
+    pub id: i64,
+    pub user_id: i64,
+    pub user_name: String,
+    pub ip: String,
+    pub user_agent: String,
+    pub created_at: Option<NaiveDateTime>,
+    pub last_seen_at: Option<NaiveDateTime>,
+    pub impersonator_id: Option<i64>,
+}
+
+/// Client of a login or refresh request.
+pub struct SessionInfo {
+    pub ip: String,
+    pub user_agent: String,
+}
+
+/// Sessions seen and killed by this instance. Killed sessions fail at once
+/// here, other instances refuse them at the next refresh.
+#[derive(Default)]
+pub struct SessionRegistry {
+    seen: Mutex<HashMap<i64, NaiveDateTime>>,
+    killed: Mutex<HashSet<i64>>,
+}
+
+impl SessionInfo {
+    pub fn from_request(req: &Request<Body>) -> Self {
+        SessionInfo {
+            ip: req.remote_addr().ip().to_string(),
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+}
+
+impl SessionRegistry {
+    /// Marks the session as seen now, `false` for a killed one.
+    pub fn touch(&self, id: i64) -> bool {
+        if matches!(self.killed.lock(), Ok(killed) if killed.contains(&id)) {
+            return false;
+        }
+        if let Ok(mut seen) = self.seen.lock() {
+            seen.insert(id, Local::now().naive_local());
+        }
+        true
+    }
+
+    fn kill(&self, ids: &[i64]) {
+        if let Ok(mut killed) = self.killed.lock() {
+            killed.extend(ids);
+        }
+        if let Ok(mut seen) = self.seen.lock() {
+            for id in ids {
+                seen.remove(id);
+            }
+        }
+    }
+
+    fn last_seen(&self, id: i64) -> Option<NaiveDateTime> {
+        self.seen.lock().ok()?.get(&id).copied()
+    }
+}
+
+pub async fn create_session(
+    pool: &RpelPool,
+    user_id: i64,
+    info: &SessionInfo,
+) -> Result<i64, ServiceError> {
+    let client = pool.get().await?;
+    Ok(client
+        .query_one(
+            "INSERT INTO sessions (user_id, ip, user_agent) VALUES ($1, $2, $3) RETURNING id",
+            &[&user_id, &info.ip, &info.user_agent],
+        )
+        .await?
+        .get("id"))
+}
+
+/// Session of an admin acting as the user, it ends after `secs`.
+pub async fn create_impersonation(
+    pool: &RpelPool,
+    user_id: i64,
+    impersonator_id: i64,
+    secs: i64,
+    info: &SessionInfo,
+) -> Result<i64, ServiceError> {
+    let client = pool.get().await?;
+    Ok(client
+        .query_one(
+            "INSERT INTO sessions (user_id, ip, user_agent, impersonator_id, expires_at)
+            VALUES ($1, $2, $3, $4, now() + $5::bigint * interval '1 second')
+            RETURNING id",
+            &[
+                &user_id,
+                &info.ip,
+                &info.user_agent,
+                &impersonator_id,
+                &secs,
+            ],
+        )
+        .await?
+        .get("id"))
+}
+
+/// Records the client of a refresh, fails for a killed session.
+pub async fn refresh_session(
+    pool: &RpelPool,
+    id: i64,
+    info: &SessionInfo,
+) -> Result<(), ServiceError> {
+    let client = pool.get().await?;
+    let updated = client
+        .execute(
+            "UPDATE sessions SET ip = $2, user_agent = $3, last_seen_at = now()
+            WHERE id = $1 AND NOT revoked",
+            &[&id, &info.ip, &info.user_agent],
+        )
+        .await?;
+    if updated == 0 {
+        return Err(ServiceError::NotAuth);
+    }
+    Ok(())
+}
+
+/// Sessions with a valid refresh token and running impersonations, the last
+/// seen time of this instance is used when it is newer.
+pub async fn list_sessions(
+    pool: &RpelPool,
+    registry: &SessionRegistry,
+) -> Result<Vec<Session>, ServiceError> {
+    let client = pool.get().await?;
+    let rows = client
+        .query(
+            "SELECT
+                s.id,
+                s.user_id,
+                u.name AS user_name,
+                s.ip,
+                s.user_agent,
+                s.created_at,
+                s.last_seen_at,
+                s.impersonator_id
+            FROM
+                sessions AS s
+            JOIN
+                users AS u ON u.id = s.user_id
+            WHERE
+                NOT s.revoked
+            AND (
+                EXISTS (
+                    SELECT 1 FROM refresh_tokens AS r
+                    WHERE r.session_id = s.id AND NOT r.revoked AND r.expires_at > now()
+                )
+                OR s.expires_at > now()
+            )
+            ORDER BY
+                s.last_seen_at DESC",
+            &[],
+        )
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let id = row.get("id");
+            let last_seen_at: Option<NaiveDateTime> = row.get("last_seen_at");
+            Session {
+                id,
+                user_id: row.get("user_id"),
+                user_name: row.get("user_name"),
+                ip: row.get("ip"),
+                user_agent: row.get("user_agent"),
+                created_at: row.get("created_at"),
+                last_seen_at: last_seen_at.max(registry.last_seen(id)),
+                impersonator_id: row.get("impersonator_id"),
+            }
+        })
+        .collect())
+}
+
+async fn kill(
+    pool: &RpelPool,
+    registry: &SessionRegistry,
+    query: &str,
+    param: &(dyn ToSql + Sync),
+) -> Result<u64, ServiceError> {
+    let client = pool.get().await?;
+    let ids: Vec<i64> = client
+        .query(query, &[param])
+        .await?
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+    client
+        .execute(
+            "UPDATE refresh_tokens SET revoked = true
+            WHERE session_id = ANY($1) AND NOT revoked",
+            &[&ids],
+        )
+        .await?;
+    registry.kill(&ids);
+    Ok(ids.len() as u64)
+}
+
+pub async fn kill_session(
+    pool: &RpelPool,
+    registry: &SessionRegistry,
+    id: i64,
+) -> Result<u64, ServiceError> {
+    kill(
+        pool,
+        registry,
+        "UPDATE sessions SET revoked = true WHERE id = $1 AND NOT revoked RETURNING id",
+        &id,
+    )
+    .await
+}
+
+/// Ends the session of a refresh token.
+pub async fn kill_refresh_session(
+    pool: &RpelPool,
+    registry: &SessionRegistry,
+    token_hash: &str,
+) -> Result<u64, ServiceError> {
+    kill(
+        pool,
+        registry,
+        "UPDATE sessions SET revoked = true
+        WHERE id = (SELECT session_id FROM refresh_tokens WHERE token_hash = $1)
+        AND NOT revoked
+        RETURNING id",
+        &token_hash,
+    )
+    .await
+}
+
+/// Ends all sessions of the user.
+pub async fn logout_user(
+    pool: &RpelPool,
+    registry: &SessionRegistry,
+    user_id: i64,
+) -> Result<u64, ServiceError> {
+    kill(
+        pool,
+        registry,
+        "UPDATE sessions SET revoked = true WHERE user_id = $1 AND NOT revoked RETURNING id",
+        &user_id,
+    )
+    .await
+}
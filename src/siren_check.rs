@@ -0,0 +1,266 @@
+use chrono::NaiveDate;
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::Row;
+
+use crate::{config, error::ServiceError};
+
+const DEFAULT_CHECK_DAYS: i32 = 365;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SirenCheck {
+    #[serde(default)]
+    pub id: i64,
+    pub siren_id: i64,
+    pub check_date: NaiveDate,
+    pub contact_id: Option<i64>,
+    pub result: Option<String>,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SirenCheckList {
+    pub id: i64,
+    pub siren_id: i64,
+    pub siren_address: Option<String>,
+    pub check_date: NaiveDate,
+    pub contact_id: Option<i64>,
+    pub contact_name: Option<String>,
+    pub result: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SirenOverdue {
+    pub siren_id: i64,
+    pub num_id: Option<i64>,
+    pub siren_type_id: Option<i64>,
+    pub siren_type_name: Option<String>,
+    pub address: Option<String>,
+    pub last_check: Option<NaiveDate>,
+    pub due_date: Option<NaiveDate>,
+}
+
+impl SirenCheck {
+    pub async fn get(pool: &RpelPool, id: i64) -> Result<SirenCheck, ServiceError> {
+        let client = pool.get().await?;
+        let row = client
+            .query_one(
+                "SELECT
+                    id,
+                    siren_id,
+                    check_date,
+                    contact_id,
+                    result,
+                    note
+                FROM
+                    siren_checks
+                WHERE
+                    id = $1",
+                &[&id],
+            )
+            .await?;
+        Ok(SirenCheck {
+            id: row.get("id"),
+            siren_id: row.get("siren_id"),
+            check_date: row.get("check_date"),
+            contact_id: row.get("contact_id"),
+            result: row.get("result"),
+            note: row.get("note"),
+        })
+    }
+
+    pub async fn insert(pool: &RpelPool, item: SirenCheck) -> Result<SirenCheck, ServiceError> {
+        let mut item = item;
+        let client = pool.get().await?;
+        let row = client
+            .query_one(
+                "INSERT INTO siren_checks
+                (
+                    siren_id,
+                    check_date,
+                    contact_id,
+                    result,
+                    note,
+                    created_at,
+                    updated_at
+                )
+                VALUES
+                (
+                    $1, $2, $3, $4, $5, now(), now()
+                )
+                RETURNING
+                    id",
+                &[
+                    &item.siren_id,
+                    &item.check_date,
+                    &item.contact_id,
+                    &item.result,
+                    &item.note,
+                ],
+            )
+            .await?;
+        item.id = row.get("id");
+        Ok(item)
+    }
+
+    pub async fn update(pool: &RpelPool, item: SirenCheck) -> Result<u64, ServiceError> {
+        let client = pool.get().await?;
+        Ok(client
+            .execute(
+                "UPDATE siren_checks SET
+                    siren_id = $2,
+                    check_date = $3,
+                    contact_id = $4,
+                    result = $5,
+                    note = $6,
+                    updated_at = now()
+                WHERE
+                    id = $1",
+                &[
+                    &item.id,
+                    &item.siren_id,
+                    &item.check_date,
+                    &item.contact_id,
+                    &item.result,
+                    &item.note,
+                ],
+            )
+            .await?)
+    }
+
+    pub async fn delete(pool: &RpelPool, id: i64) -> Result<u64, ServiceError> {
+        let client = pool.get().await?;
+        Ok(client
+            .execute("DELETE FROM siren_checks WHERE id = $1", &[&id])
+            .await?)
+    }
+}
+
+const SIREN_CHECK_LIST: &str = "
+    SELECT
+        sc.id,
+        sc.siren_id,
+        s.address AS siren_address,
+        sc.check_date,
+        sc.contact_id,
+        c.name AS contact_name,
+        sc.result
+    FROM
+        siren_checks AS sc
+    LEFT JOIN
+        sirens AS s ON s.id = sc.siren_id
+    LEFT JOIN
+        contacts AS c ON c.id = sc.contact_id";
+
+impl SirenCheckList {
+    fn from_row(row: &Row) -> Self {
+        SirenCheckList {
+            id: row.get("id"),
+            siren_id: row.get("siren_id"),
+            siren_address: row.get("siren_address"),
+            check_date: row.get("check_date"),
+            contact_id: row.get("contact_id"),
+            contact_name: row.get("contact_name"),
+            result: row.get("result"),
+        }
+    }
+
+    pub async fn get_all(pool: &RpelPool) -> Result<Vec<SirenCheckList>, ServiceError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                format!("{SIREN_CHECK_LIST} ORDER BY sc.check_date DESC, sc.id DESC").as_str(),
+                &[],
+            )
+            .await?;
+        Ok(rows.iter().map(SirenCheckList::from_row).collect())
+    }
+
+    pub async fn get_by_siren(
+        pool: &RpelPool,
+        siren_id: i64,
+    ) -> Result<Vec<SirenCheckList>, ServiceError> {
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                format!(
+                    "{SIREN_CHECK_LIST}
+                    WHERE sc.siren_id = $1
+                    ORDER BY sc.check_date DESC, sc.id DESC"
+                )
+                .as_str(),
+                &[&siren_id],
+            )
+            .await?;
+        Ok(rows.iter().map(SirenCheckList::from_row).collect())
+    }
+}
+
+impl SirenOverdue {
+    /// Sirens never inspected or inspected longer ago than the interval of
+    /// their type. Intervals are read from `RGO_SIREN_CHECK_INTERVALS` as
+    /// `siren_type_id:days` pairs, falling back to `RGO_SIREN_CHECK_DAYS`.
+    pub async fn get_all(pool: &RpelPool) -> Result<Vec<SirenOverdue>, ServiceError> {
+        let default_days = config::var_or("RGO_SIREN_CHECK_DAYS", DEFAULT_CHECK_DAYS);
+        let (type_ids, days): (Vec<i64>, Vec<i32>) =
+            config::var_pairs::<i64, i32>("RGO_SIREN_CHECK_INTERVALS")
+                .into_iter()
+                .unzip();
+        let client = pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT
+                    o.siren_id,
+                    o.num_id,
+                    o.siren_type_id,
+                    o.siren_type_name,
+                    o.address,
+                    o.last_check,
+                    o.last_check + o.days AS due_date
+                FROM (
+                    SELECT
+                        s.id AS siren_id,
+                        s.num_id::bigint AS num_id,
+                        s.siren_type_id,
+                        st.name AS siren_type_name,
+                        s.address,
+                        c.last_check,
+                        COALESCE(i.days, $3::int) AS days
+                    FROM
+                        sirens AS s
+                    LEFT JOIN
+                        siren_types AS st ON st.id = s.siren_type_id
+                    LEFT JOIN (
+                        SELECT
+                            siren_id,
+                            MAX(check_date) AS last_check
+                        FROM
+                            siren_checks
+                        GROUP BY
+                            siren_id
+                    ) AS c ON c.siren_id = s.id
+                    LEFT JOIN
+                        UNNEST($1::bigint[], $2::int[]) AS i(siren_type_id, days)
+                        ON i.siren_type_id = s.siren_type_id
+                ) AS o
+                WHERE
+                    o.last_check IS NULL OR o.last_check + o.days < CURRENT_DATE
+                ORDER BY
+                    due_date NULLS FIRST, o.siren_id",
+                &[&type_ids, &days, &default_days],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| SirenOverdue {
+                siren_id: row.get("siren_id"),
+                num_id: row.get("num_id"),
+                siren_type_id: row.get("siren_type_id"),
+                siren_type_name: row.get("siren_type_name"),
+                address: row.get("address"),
+                last_check: row.get("last_check"),
+                due_date: row.get("due_date"),
+            })
+            .collect())
+    }
+}
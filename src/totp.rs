@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use hyper::{Body, Response};
+use rand::{distributions::Alphanumeric, thread_rng, Rng, RngCore};
+use rpel::RpelPool;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::{config, error::ServiceError, services::json_response, users::UserData};
+
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+const WINDOW: u64 = 1;
+const RECOVERY_CODES: usize = 10;
+const PENDING_TTL: Duration = Duration::from_secs(300);
+const PENDING_ATTEMPTS: u32 = 5;
+
+#[derive(Deserialize, Serialize)]
+pub enum TotpObject {
+    Enroll,
+    Confirm(String),
+    Disable(String),
+    Reset(i64),
+}
+
+/// Reply of `/go/login` when the second factor is still required.
+#[derive(Serialize)]
+pub struct TotpRequired {
+    pub totp: String,
+}
+
+/// Body of `/go/login/totp`, `c` is a TOTP or a recovery code.
+#[derive(Deserialize)]
+pub struct TotpLogin {
+    pub totp: String,
+    pub c: String,
+}
+
+#[derive(Serialize)]
+struct Enrollment {
+    secret: String,
+    uri: String,
+}
+
+struct Pending {
+    user: UserData,
+    created: Instant,
+    attempts: u32,
+}
+
+/// Logins waiting for the second factor, keyed by a one-off pending token.
+/// They are kept in the memory of the instance, so behind a load balancer
+/// both login steps have to reach the same instance (sticky sessions).
+#[derive(Default)]
+pub struct TotpPending {
+    values: Mutex<HashMap<String, Pending>>,
+}
+
+fn random_string(len: usize) -> String {
+    thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn hash_code(code: &str) -> String {
+    format!(
+        "{:x}",
+        Sha256::digest(code.trim().to_lowercase().as_bytes())
+    )
+}
+
+fn current_step() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / STEP
+}
+
+/// HOTP value of RFC 4226 for the counter `step`.
+fn hotp(key: &[u8], step: u64) -> u32 {
+    let Ok(mut mac) = Hmac::<Sha1>::new_from_slice(key) else {
+        return 0;
+    };
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    value % 10u32.pow(DIGITS)
+}
+
+/// Step of a matching code within the allowed clock drift.
+fn verify(secret: &str, code: &str, now: u64) -> Option<u64> {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code: u32 = code.trim().parse().ok()?;
+    (now.saturating_sub(WINDOW)..=now + WINDOW).find(|step| hotp(&key, *step) == code)
+}
+
+fn encode_uri(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn provisioning_uri(name: &str, secret: &str) -> String {
+    let issuer = encode_uri(&config::var_or("RGO_TOTP_ISSUER", String::from("rgo")));
+    format!(
+        "otpauth://totp/{issuer}:{}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP}",
+        encode_uri(name)
+    )
+}
+
+/// Only accounts with user management rights use the second factor.
+fn is_admin(user: &UserData) -> bool {
+    user.role >> 6 > 0
+}
+
+pub async fn is_enabled(pool: &RpelPool, user: &UserData) -> Result<bool, ServiceError> {
+    if !is_admin(user) {
+        return Ok(false);
+    }
+    let client = pool.get().await?;
+    Ok(client
+        .query_opt(
+            "SELECT 1 FROM user_totp WHERE user_id = $1 AND enabled",
+            &[&user.id],
+        )
+        .await?
+        .is_some())
+}
+
+/// Accepts a code not used before or one of the recovery codes, which is
+/// spent by the use.
+async fn check_code(
+    pool: &RpelPool,
+    user_id: i64,
+    code: &str,
+    enabled: bool,
+) -> Result<bool, ServiceError> {
+    let client = pool.get().await?;
+    let Some(row) = client
+        .query_opt(
+            "SELECT secret, last_step FROM user_totp WHERE user_id = $1 AND enabled = $2",
+            &[&user_id, &enabled],
+        )
+        .await?
+    else {
+        return Ok(false);
+    };
+    let secret: String = row.get("secret");
+    let last_step: i64 = row.get("last_step");
+    if let Some(step) =
+        verify(&secret, code, current_step()).filter(|step| *step as i64 > last_step)
+    {
+        // Of concurrent uses of a code only the first moves the step.
+        return Ok(client
+            .execute(
+                "UPDATE user_totp SET last_step = $2 WHERE user_id = $1 AND last_step < $2",
+                &[&user_id, &(step as i64)],
+            )
+            .await?
+            > 0);
+    }
+    if !enabled {
+        return Ok(false);
+    }
+    Ok(client
+        .execute(
+            "UPDATE user_totp SET recovery_codes = array_remove(recovery_codes, $2)
+            WHERE user_id = $1 AND $2 = ANY(recovery_codes)",
+            &[&user_id, &hash_code(code)],
+        )
+        .await?
+        > 0)
+}
+
+impl TotpPending {
+    pub fn insert(&self, user: UserData) -> String {
+        let key = random_string(32);
+        if let Ok(mut values) = self.values.lock() {
+            values.retain(|_, pending| pending.created.elapsed() < PENDING_TTL);
+            values.insert(
+                key.clone(),
+                Pending {
+                    user,
+                    created: Instant::now(),
+                    attempts: 0,
+                },
+            );
+        }
+        key
+    }
+
+    /// Name of the user of a pending login, not counted as an attempt.
+    pub fn name(&self, key: &str) -> Option<String> {
+        Some(self.values.lock().ok()?.get(key)?.user.name.clone())
+    }
+
+    /// User of a pending login, counted as an attempt. The entry is
+    /// dropped when expired or out of attempts.
+    fn attempt(&self, key: &str) -> Option<UserData> {
+        let mut values = self.values.lock().ok()?;
+        let pending = values.get_mut(key)?;
+        pending.attempts += 1;
+        if pending.created.elapsed() >= PENDING_TTL || pending.attempts > PENDING_ATTEMPTS {
+            values.remove(key);
+            return None;
+        }
+        Some(pending.user.clone())
+    }
+
+    fn remove(&self, key: &str) {
+        if let Ok(mut values) = self.values.lock() {
+            values.remove(key);
+        }
+    }
+
+    /// Second login step, returns the user for a valid code.
+    pub async fn complete(
+        &self,
+        pool: &RpelPool,
+        login: &TotpLogin,
+    ) -> Result<UserData, ServiceError> {
+        let user = self.attempt(&login.totp).ok_or(ServiceError::NotAuth)?;
+        if !check_code(pool, user.id, &login.c, true).await? {
+            return Err(ServiceError::NotAuth);
+        }
+        self.remove(&login.totp);
+        Ok(user)
+    }
+}
+
+async fn enroll(pool: &RpelPool, user: &UserData) -> Result<Enrollment, ServiceError> {
+    let mut key = [0u8; 20];
+    thread_rng().fill_bytes(&mut key);
+    let secret = BASE32_NOPAD.encode(&key);
+    let client = pool.get().await?;
+    let updated = client
+        .execute(
+            "INSERT INTO user_totp (user_id, secret) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET secret = $2, last_step = 0
+            WHERE NOT user_totp.enabled",
+            &[&user.id, &secret],
+        )
+        .await?;
+    if updated == 0 {
+        return Err(ServiceError::BadRequest(
+            "two-factor authentication is already enabled".to_string(),
+        ));
+    }
+    Ok(Enrollment {
+        uri: provisioning_uri(&user.name, &secret),
+        secret,
+    })
+}
+
+/// Enables the enrolled secret after a valid code and returns new recovery
+/// codes, only their hashes are kept.
+async fn confirm(
+    pool: &RpelPool,
+    user: &UserData,
+    code: &str,
+) -> Result<Vec<String>, ServiceError> {
+    if !check_code(pool, user.id, code, false).await? {
+        return Err(ServiceError::NotAuth);
+    }
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| random_string(10).to_lowercase())
+        .collect();
+    let hashes: Vec<String> = codes.iter().map(|code| hash_code(code)).collect();
+    let client = pool.get().await?;
+    client
+        .execute(
+            "UPDATE user_totp SET enabled = true, recovery_codes = $2 WHERE user_id = $1",
+            &[&user.id, &hashes],
+        )
+        .await?;
+    Ok(codes)
+}
+
+async fn reset(pool: &RpelPool, user_id: i64) -> Result<u64, ServiceError> {
+    let client = pool.get().await?;
+    Ok(client
+        .execute("DELETE FROM user_totp WHERE user_id = $1", &[&user_id])
+        .await?)
+}
+
+pub async fn totp_cmd(
+    obj: TotpObject,
+    user: &UserData,
+    pool: &RpelPool,
+) -> Result<Response<Body>, ServiceError> {
+    let (command, object) = match obj {
+        TotpObject::Enroll => ("Enroll", json!(enroll(pool, user).await?)),
+        TotpObject::Confirm(code) => ("Confirm", json!(confirm(pool, user, &code).await?)),
+        TotpObject::Disable(code) => {
+            if !check_code(pool, user.id, &code, true).await? {
+                return Err(ServiceError::NotAuth);
+            }
+            ("Disable", json!(reset(pool, user.id).await?))
+        }
+        TotpObject::Reset(user_id) => ("Reset", json!(reset(pool, user_id).await?)),
+    };
+    json_response(json!({
+        "command": command,
+        "name": "Totp",
+        "object": object,
+        "error": "",
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn hotp_matches_rfc_4226() {
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (step, code) in expected.into_iter().enumerate() {
+            assert_eq!(hotp(RFC_KEY, step as u64), code, "step {step}");
+        }
+    }
+
+    #[test]
+    fn verify_allows_clock_drift_of_the_window() {
+        let secret = BASE32_NOPAD.encode(RFC_KEY);
+        assert_eq!(verify(&secret, "969429", 3), Some(3));
+        assert_eq!(verify(&secret, " 969429 ", 4), Some(3));
+        assert_eq!(verify(&secret, "969429", 2), Some(3));
+        assert_eq!(verify(&secret, "969429", 5), None);
+        assert_eq!(verify(&secret, "not a code", 3), None);
+        assert_eq!(verify("not base32!", "969429", 3), None);
+    }
+}
@@ -1,6 +1,7 @@
-use std::collections::HashMap;
+use std::sync::Arc;
 
 use hyper::{Body, Response};
+use log::warn;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use rpel::{
     user::{User, UserList},
@@ -8,13 +9,27 @@ use rpel::{
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use tokio_postgres::{error::SqlState, Row};
 
+use crate::apikey::{ApiKey, ApiKeyParams, NewApiKey};
+use crate::attachment::AttachmentObject;
+use crate::auth::A;
+use crate::guard::LoginGuard;
+use crate::jwt::{impersonation_token, revoke_user_tokens, TokenKeys};
 use crate::messages::Command;
+use crate::session::{
+    kill_session, list_sessions, logout_user, Session, SessionInfo, SessionRegistry,
+};
+use crate::totp::{is_enabled, TotpObject};
 use crate::{error::ServiceError, services::json_response};
 
+/// Keys of the access tokens and the sessions seen by this instance, shared
+/// by clones. Users themselves are read from the table at login, so changes
+/// apply to the next login.
 #[derive(Clone)]
 pub struct Users {
-    values: HashMap<String, UserData>,
+    keys: Arc<TokenKeys>,
+    sessions: Arc<SessionRegistry>,
 }
 
 #[derive(Clone)]
@@ -23,6 +38,16 @@ pub struct UserData {
     pub name: String,
     pub key: String,
     pub role: i64,
+    pub company_id: Option<i64>,
+    pub department_id: Option<i64>,
+    pub impersonator: Option<Impersonator>,
+}
+
+/// Admin acting as another user.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Impersonator {
+    pub id: i64,
+    pub name: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -32,6 +57,42 @@ pub enum UserObject {
     InsertUser(User),
     UpdateUser(User),
     DeleteUser(i64),
+    SetUserScope {
+        user_id: i64,
+        company_id: Option<i64>,
+        department_id: Option<i64>,
+    },
+    GetApiKeys(i64),
+    UnlockLogin(String),
+    InsertApiKey(ApiKeyParams),
+    RevokeApiKey(i64),
+    Me,
+    ChangeMyPassword {
+        old: String,
+        new: String,
+    },
+    UpdateMyProfile(MyProfile),
+    ListSessions,
+    KillSession(i64),
+    LogoutUser(i64),
+    Impersonate(i64),
+}
+
+/// The user of the token.
+#[derive(Serialize, Deserialize)]
+pub struct Me {
+    pub id: i64,
+    pub name: String,
+    pub role: i64,
+    pub company_id: Option<i64>,
+    pub department_id: Option<i64>,
+    pub totp: bool,
+}
+
+/// Fields users may change about themselves.
+#[derive(Serialize, Deserialize)]
+pub struct MyProfile {
+    pub name: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -40,10 +101,29 @@ pub enum DbUserObject {
     User(User),
     UserList(Vec<UserList>),
     Id(i64),
+    ApiKeyList(Vec<ApiKey>),
+    ApiKey(NewApiKey),
+    Me(Me),
+    SessionList(Vec<Session>),
+    Tokens(A),
 }
 
 impl UserData {
     pub fn permissions(&self, command: Command) -> Result<Command, ServiceError> {
+        // An impersonating admin can not change the account or start another
+        // impersonation.
+        if self.impersonator.is_some()
+            && matches!(
+                command,
+                Command::User(
+                    UserObject::ChangeMyPassword { .. }
+                        | UserObject::UpdateMyProfile(_)
+                        | UserObject::Impersonate(_)
+                ) | Command::Totp(_)
+            )
+        {
+            return Err(ServiceError::NotPermission);
+        }
         if match &command {
             Command::GetItem(_) => self.role >> 1 > 0,
             Command::GetList(_) => self.role >> 2 > 0,
@@ -55,6 +135,34 @@ impl UserData {
             Command::User(UserObject::InsertUser(_)) => self.role >> 7 > 0,
             Command::User(UserObject::UpdateUser(_)) => self.role >> 8 > 0,
             Command::User(UserObject::DeleteUser(_)) => self.role >> 9 > 0,
+            Command::User(UserObject::SetUserScope { .. }) => self.role >> 8 > 0,
+            Command::User(UserObject::GetApiKeys(_)) => self.role >> 6 > 0,
+            Command::User(UserObject::UnlockLogin(_)) => self.role >> 8 > 0,
+            Command::User(UserObject::InsertApiKey(_)) => self.role >> 7 > 0,
+            Command::User(UserObject::RevokeApiKey(_)) => self.role >> 8 > 0,
+            Command::User(UserObject::Me) => true,
+            Command::User(UserObject::ChangeMyPassword { .. }) => true,
+            Command::User(UserObject::UpdateMyProfile(_)) => true,
+            Command::User(UserObject::ListSessions) => self.role >> 8 > 0,
+            Command::User(UserObject::KillSession(_)) => self.role >> 8 > 0,
+            Command::User(UserObject::LogoutUser(_)) => self.role >> 8 > 0,
+            Command::User(UserObject::Impersonate(_)) => self.role >> 8 > 0,
+            Command::Totp(TotpObject::Reset(_)) => self.role >> 8 > 0,
+            Command::Totp(_) => self.role >> 6 > 0,
+            Command::Geo(_) => self.role >> 2 > 0,
+            Command::Dashboard => self.role >> 2 > 0,
+            Command::Report(_) => self.role >> 2 > 0,
+            Command::Pdf(_) => self.role >> 1 > 0,
+            Command::OrgChart(_) => self.role >> 1 > 0,
+            Command::Attachment(AttachmentObject::GetAttachments { .. }) => self.role >> 2 > 0,
+            Command::Attachment(AttachmentObject::GetAttachment(_)) => self.role >> 1 > 0,
+            Command::Attachment(AttachmentObject::GetThumbnail(_)) => self.role >> 1 > 0,
+            Command::Attachment(AttachmentObject::DeleteAttachment(_)) => self.role >> 5 > 0,
+            Command::GetHistory(_) => self.role >> 1 > 0,
+            Command::GetItemAt(..) => self.role >> 1 > 0,
+            Command::RevertItem(..) => self.role >> 4 > 0,
+            Command::FindDuplicates(_) => self.role >> 2 > 0,
+            Command::MergeContacts { .. } => self.role >> 5 > 0,
         } {
             Ok(command)
         } else {
@@ -63,41 +171,136 @@ impl UserData {
     }
 }
 
-impl Users {
-    pub async fn new(pool: &RpelPool) -> Result<Users, ServiceError> {
-        let mut rng = thread_rng();
-        let users = UserList::get_all(pool).await?;
-        let mut hash_map = HashMap::new();
-        for user in users {
-            let key = (&mut rng)
-                .sample_iter(Alphanumeric)
-                .take(20)
-                .map(char::from)
-                .collect();
-            hash_map.insert(
-                key,
-                UserData {
-                    id: user.id,
-                    name: user.name.clone(),
-                    key: user.key.clone(),
-                    role: user.role,
-                },
-            );
+const USER_QUERY: &str = "
+    SELECT u.id, u.name, u.role, s.company_id, s.department_id
+    FROM users AS u
+    LEFT JOIN user_scopes AS s ON s.user_id = u.id";
+
+fn user_data(row: &Row) -> UserData {
+    UserData {
+        id: row.get("id"),
+        name: row.get("name"),
+        key: String::new(),
+        role: row.get("role"),
+        company_id: row.get("company_id"),
+        department_id: row.get("department_id"),
+        impersonator: None,
+    }
+}
+
+/// User with the current role and scope, without the key.
+pub async fn load_user(pool: &RpelPool, id: i64) -> Result<Option<UserData>, ServiceError> {
+    let client = pool.get().await?;
+    Ok(client
+        .query_opt(format!("{USER_QUERY} WHERE u.id = $1").as_str(), &[&id])
+        .await?
+        .as_ref()
+        .map(user_data))
+}
+
+/// User of the name and key of the table, without the key.
+pub async fn table_user(
+    pool: &RpelPool,
+    name: &str,
+    key: &str,
+) -> Result<Option<UserData>, ServiceError> {
+    let client = pool.get().await?;
+    Ok(client
+        .query_opt(
+            format!("{USER_QUERY} WHERE u.name = $1 AND u.key = $2").as_str(),
+            &[&name, &key],
+        )
+        .await?
+        .as_ref()
+        .map(user_data))
+}
+
+/// User linked to the `subject` of the `issuer` of another auth backend,
+/// without the key. An unknown subject gets a new user `name` with `role`
+/// and a random key, so the table backend never accepts it. Nothing is added
+/// with a zero role or when the name is taken, an existing user is never
+/// taken over by a login of another backend.
+pub async fn provision_user(
+    pool: &RpelPool,
+    issuer: &str,
+    subject: &str,
+    name: &str,
+    role: i64,
+) -> Result<Option<UserData>, ServiceError> {
+    let mut client = pool.get().await?;
+    if let Some(row) = client
+        .query_opt(
+            format!(
+                "{USER_QUERY} JOIN user_identities AS i ON i.user_id = u.id
+                WHERE i.issuer = $1 AND i.subject = $2"
+            )
+            .as_str(),
+            &[&issuer, &subject],
+        )
+        .await?
+    {
+        return Ok(Some(user_data(&row)));
+    }
+    if role == 0 {
+        return Ok(None);
+    }
+    let key: String = thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(40)
+        .map(char::from)
+        .collect();
+    let tx = client.transaction().await?;
+    let row = match tx
+        .query_one(
+            "INSERT INTO users (name, key, role) VALUES ($1, $2, $3) RETURNING id",
+            &[&name, &key, &role],
+        )
+        .await
+    {
+        Ok(row) => row,
+        Err(err) if err.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+            warn!("{issuer} login {subject:?} refused, user {name:?} exists");
+            return Ok(None);
         }
-        Ok(Users { values: hash_map })
+        Err(err) => return Err(err.into()),
+    };
+    tx.execute(
+        "INSERT INTO user_identities (issuer, subject, user_id) VALUES ($1, $2, $3)",
+        &[&issuer, &subject, &row.get::<_, i64>("id")],
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(Some(UserData {
+        id: row.get("id"),
+        name: name.to_string(),
+        key: String::new(),
+        role,
+        company_id: None,
+        department_id: None,
+        impersonator: None,
+    }))
+}
+
+impl Users {
+    pub fn new() -> Result<Users, ServiceError> {
+        Ok(Users {
+            keys: Arc::new(TokenKeys::from_env()?),
+            sessions: Arc::default(),
+        })
+    }
+
+    /// User of a valid access token of a live session.
+    pub fn get_user(&self, token: &str) -> Option<UserData> {
+        let (user, sid) = self.keys.verify(token)?;
+        self.sessions.touch(sid).then_some(user)
     }
 
-    pub fn get_user(&self, key: &str) -> Option<UserData> {
-        self.values.get(key).cloned()
+    pub fn access_token(&self, user: &UserData, sid: i64) -> Result<String, ServiceError> {
+        self.keys.access_token(user, sid)
     }
 
-    pub fn get_reply(&self, username: &str, userkey: &str) -> Option<(String, i64)> {
-        let reply = self
-            .values
-            .iter()
-            .find(|(_key, user)| user.name == username && user.key == userkey)
-            .map(|(key, user)| (key, user.role))?;
-        Some((reply.0.clone(), reply.1))
+    pub fn sessions(&self) -> &SessionRegistry {
+        &self.sessions
     }
 }
 
@@ -148,15 +351,268 @@ impl WsUserMsg {
             error: String::new(),
         }
     }
+
+    fn from_scope(object: u64) -> Self {
+        WsUserMsg {
+            command: "SetUserScope".to_string(),
+            object: DbUserObject::Id(object as i64),
+            error: String::new(),
+        }
+    }
+
+    fn from_api_keys(object: Vec<ApiKey>) -> Self {
+        WsUserMsg {
+            command: "GetApiKeys".to_string(),
+            object: DbUserObject::ApiKeyList(object),
+            error: String::new(),
+        }
+    }
+
+    fn from_new_api_key(object: NewApiKey) -> Self {
+        WsUserMsg {
+            command: "InsertApiKey".to_string(),
+            object: DbUserObject::ApiKey(object),
+            error: String::new(),
+        }
+    }
+
+    fn from_unlock(object: bool) -> Self {
+        WsUserMsg {
+            command: "UnlockLogin".to_string(),
+            object: DbUserObject::Id(object as i64),
+            error: String::new(),
+        }
+    }
+
+    fn from_revoke(object: u64) -> Self {
+        WsUserMsg {
+            command: "RevokeApiKey".to_string(),
+            object: DbUserObject::Id(object as i64),
+            error: String::new(),
+        }
+    }
+
+    fn from_me(object: Me) -> Self {
+        WsUserMsg {
+            command: "Me".to_string(),
+            object: DbUserObject::Me(object),
+            error: String::new(),
+        }
+    }
+
+    fn from_sessions(object: Vec<Session>) -> Self {
+        WsUserMsg {
+            command: "ListSessions".to_string(),
+            object: DbUserObject::SessionList(object),
+            error: String::new(),
+        }
+    }
+
+    fn from_tokens(object: A) -> Self {
+        WsUserMsg {
+            command: "Impersonate".to_string(),
+            object: DbUserObject::Tokens(object),
+            error: String::new(),
+        }
+    }
+
+    fn from_count(command: &str, object: u64) -> Self {
+        WsUserMsg {
+            command: command.to_string(),
+            object: DbUserObject::Id(object as i64),
+            error: String::new(),
+        }
+    }
+}
+
+/// Limits the user to a company and/or department, both `None` removes the
+/// limit. Tokens carry the scope, so the sessions of the user are ended;
+/// logins and refreshes read the new scope from `user_scopes`.
+async fn set_user_scope(
+    pool: &RpelPool,
+    user_id: i64,
+    company_id: Option<i64>,
+    department_id: Option<i64>,
+) -> Result<u64, ServiceError> {
+    let client = pool.get().await?;
+    if company_id.is_none() && department_id.is_none() {
+        return Ok(client
+            .execute("DELETE FROM user_scopes WHERE user_id = $1", &[&user_id])
+            .await?);
+    }
+    Ok(client
+        .execute(
+            "INSERT INTO user_scopes (user_id, company_id, department_id) VALUES ($1, $2, $3)
+            ON CONFLICT (user_id) DO UPDATE SET company_id = $2, department_id = $3",
+            &[&user_id, &company_id, &department_id],
+        )
+        .await?)
+}
+
+async fn me(pool: &RpelPool, user: &UserData) -> Result<Me, ServiceError> {
+    Ok(Me {
+        id: user.id,
+        name: user.name.clone(),
+        role: user.role,
+        company_id: user.company_id,
+        department_id: user.department_id,
+        totp: is_enabled(pool, user).await?,
+    })
+}
+
+/// Sets a new key after the current one is given. Refresh tokens of the
+/// user are revoked, so other sessions end with their access tokens.
+async fn change_my_password(
+    pool: &RpelPool,
+    user: &UserData,
+    old: &str,
+    new: &str,
+) -> Result<u64, ServiceError> {
+    if new.trim().is_empty() {
+        return Err(ServiceError::BadRequest(
+            "new password is empty".to_string(),
+        ));
+    }
+    let client = pool.get().await?;
+    let updated = client
+        .execute(
+            "UPDATE users SET key = $3 WHERE id = $1 AND key = $2",
+            &[&user.id, &old, &new],
+        )
+        .await?;
+    if updated == 0 {
+        return Err(ServiceError::NotAuth);
+    }
+    revoke_user_tokens(pool, user.id).await?;
+    Ok(updated)
+}
+
+async fn update_my_profile(
+    pool: &RpelPool,
+    user: &UserData,
+    profile: MyProfile,
+) -> Result<u64, ServiceError> {
+    let name = profile.name.trim();
+    if name.is_empty() {
+        return Err(ServiceError::BadRequest("name is empty".to_string()));
+    }
+    let client = pool.get().await?;
+    client
+        .execute(
+            "UPDATE users SET name = $2 WHERE id = $1",
+            &[&user.id, &name],
+        )
+        .await
+        .map_err(|err| match err.code() {
+            Some(&SqlState::UNIQUE_VIOLATION) => {
+                ServiceError::BadRequest(format!("name {name} is taken"))
+            }
+            _ => err.into(),
+        })
 }
 
-pub async fn user_cmd(obj: UserObject, pool: &RpelPool) -> Result<Response<Body>, ServiceError> {
+/// Token of the target user for the admin. The target may only have rights
+/// and a scope the admin has too.
+async fn impersonate(
+    pool: &RpelPool,
+    users: &Users,
+    admin: &UserData,
+    user_id: i64,
+    info: &SessionInfo,
+) -> Result<A, ServiceError> {
+    let mut target = load_user(pool, user_id)
+        .await?
+        .ok_or_else(|| ServiceError::BadRequest(format!("no user {user_id}")))?;
+    let in_scope = |own: Option<i64>, other: Option<i64>| own.is_none() || own == other;
+    if target.id == admin.id
+        || target.role > admin.role
+        || !in_scope(admin.company_id, target.company_id)
+        || !in_scope(admin.department_id, target.department_id)
+    {
+        return Err(ServiceError::NotPermission);
+    }
+    target.impersonator = Some(Impersonator {
+        id: admin.id,
+        name: admin.name.clone(),
+    });
+    warn!(
+        "user {} ({}) impersonates {} ({}) from {}",
+        admin.name, admin.id, target.name, target.id, info.ip
+    );
+    impersonation_token(pool, users, &target, info).await
+}
+
+pub async fn user_cmd(
+    obj: UserObject,
+    user: &UserData,
+    users: &Users,
+    pool: &RpelPool,
+    guard: &LoginGuard,
+    info: &SessionInfo,
+) -> Result<Response<Body>, ServiceError> {
     let a = match obj {
         UserObject::GetUser(id) => WsUserMsg::from_get(User::get(pool, id).await?),
         UserObject::GetUserList => WsUserMsg::from_list(UserList::get_all(pool).await?),
         UserObject::InsertUser(item) => WsUserMsg::from_insert(User::insert(pool, item).await?),
-        UserObject::UpdateUser(item) => WsUserMsg::from_update(User::update(pool, item).await?),
-        UserObject::DeleteUser(id) => WsUserMsg::from_delete(User::delete(pool, id).await?),
+        UserObject::UpdateUser(item) => {
+            // Tokens carry name and role, so a change needs a new login. The
+            // sessions end after the update, a login in between would get
+            // the old values.
+            let id = item.id;
+            let changed = matches!(
+                load_user(pool, id).await?,
+                Some(known) if known.role != item.role || known.name != item.name
+            );
+            let updated = User::update(pool, item).await?;
+            if changed {
+                logout_user(pool, users.sessions(), id).await?;
+            }
+            WsUserMsg::from_update(updated)
+        }
+        UserObject::DeleteUser(id) => {
+            logout_user(pool, users.sessions(), id).await?;
+            WsUserMsg::from_delete(User::delete(pool, id).await?)
+        }
+        UserObject::SetUserScope {
+            user_id,
+            company_id,
+            department_id,
+        } => {
+            let updated = set_user_scope(pool, user_id, company_id, department_id).await?;
+            logout_user(pool, users.sessions(), user_id).await?;
+            WsUserMsg::from_scope(updated)
+        }
+        UserObject::GetApiKeys(user_id) => {
+            WsUserMsg::from_api_keys(ApiKey::get_by_user(pool, user_id).await?)
+        }
+        UserObject::InsertApiKey(params) => {
+            WsUserMsg::from_new_api_key(ApiKey::insert(pool, params).await?)
+        }
+        UserObject::RevokeApiKey(id) => WsUserMsg::from_revoke(ApiKey::revoke(pool, id).await?),
+        UserObject::UnlockLogin(key) => WsUserMsg::from_unlock(guard.unlock(&key)),
+        UserObject::Me => WsUserMsg::from_me(me(pool, user).await?),
+        UserObject::ChangeMyPassword { old, new } => WsUserMsg::from_count(
+            "ChangeMyPassword",
+            change_my_password(pool, user, &old, &new).await?,
+        ),
+        UserObject::UpdateMyProfile(profile) => WsUserMsg::from_count(
+            "UpdateMyProfile",
+            update_my_profile(pool, user, profile).await?,
+        ),
+        UserObject::ListSessions => {
+            WsUserMsg::from_sessions(list_sessions(pool, users.sessions()).await?)
+        }
+        UserObject::KillSession(id) => WsUserMsg::from_count(
+            "KillSession",
+            kill_session(pool, users.sessions(), id).await?,
+        ),
+        UserObject::LogoutUser(user_id) => WsUserMsg::from_count(
+            "LogoutUser",
+            logout_user(pool, users.sessions(), user_id).await?,
+        ),
+        UserObject::Impersonate(user_id) => {
+            WsUserMsg::from_tokens(impersonate(pool, users, user, user_id, info).await?)
+        }
     };
     json_response(json!(a))
 }
@@ -0,0 +1,619 @@
+use std::fmt;
+
+use chrono::NaiveDate;
+use rpel::{
+    certificate::Certificate, company::Company, contact::Contact, department::Department,
+    education::Education, kind::Kind, post::Post, practice::Practice, rank::Rank, scope::Scope,
+    siren::Siren, siren_type::SirenType, user::User,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    config, dbo::DbObject, error::ServiceError, hierarchy::ParentLink, messages::Item,
+    siren_check::SirenCheck,
+};
+
+const DEFAULT_COUNTRY: &str = "7";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl FieldError {
+    pub fn new(field: &str, code: &str, message: &str) -> Self {
+        FieldError {
+            field: field.to_string(),
+            code: code.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+pub enum Rule {
+    /// Field must be present, not null and not an empty string.
+    Required(&'static str),
+    /// Optional reference, when set it must be a positive id.
+    Id(&'static str),
+    /// Second date must not be before the first one.
+    DateOrder(&'static str, &'static str),
+    /// Number, or string holding a number, must be within bounds.
+    Range(&'static str, f64, f64),
+}
+
+/// Declarative checks of an entity, run by `insert_item` and `update_item`.
+pub trait Validate: Serialize {
+    const RULES: &'static [Rule];
+
+    fn validate(&self) -> Vec<FieldError> {
+        match serde_json::to_value(self) {
+            Ok(Value::Object(fields)) => check(&fields, Self::RULES),
+            _ => vec![FieldError::new("", "invalid", "object is not serializable")],
+        }
+    }
+}
+
+impl Validate for Certificate {
+    const RULES: &'static [Rule] = &[Rule::Id("contact_id"), Rule::Id("company_id")];
+}
+
+impl Validate for Company {
+    const RULES: &'static [Rule] = &[Rule::Required("name"), Rule::Id("scope_id")];
+}
+
+impl Validate for Contact {
+    const RULES: &'static [Rule] = &[
+        Rule::Required("name"),
+        Rule::Id("company_id"),
+        Rule::Id("department_id"),
+        Rule::Id("post_id"),
+        Rule::Id("post_go_id"),
+        Rule::Id("rank_id"),
+    ];
+}
+
+impl Validate for Department {
+    const RULES: &'static [Rule] = &[Rule::Required("name")];
+}
+
+impl Validate for Education {
+    const RULES: &'static [Rule] = &[
+        Rule::Required("contact_id"),
+        Rule::Id("contact_id"),
+        Rule::Required("start_date"),
+        Rule::Required("end_date"),
+        Rule::DateOrder("start_date", "end_date"),
+        Rule::Id("post_id"),
+    ];
+}
+
+impl Validate for Kind {
+    const RULES: &'static [Rule] = &[Rule::Required("name")];
+}
+
+impl Validate for ParentLink {
+    const RULES: &'static [Rule] = &[Rule::Id("id"), Rule::Id("parent_id")];
+}
+
+impl Validate for Post {
+    const RULES: &'static [Rule] = &[Rule::Required("name")];
+}
+
+impl Validate for Practice {
+    const RULES: &'static [Rule] = &[
+        Rule::Required("company_id"),
+        Rule::Id("company_id"),
+        Rule::Id("kind_id"),
+        Rule::Required("date_of_practice"),
+    ];
+}
+
+impl Validate for Rank {
+    const RULES: &'static [Rule] = &[Rule::Required("name")];
+}
+
+impl Validate for Scope {
+    const RULES: &'static [Rule] = &[Rule::Required("name")];
+}
+
+impl Validate for Siren {
+    const RULES: &'static [Rule] = &[
+        Rule::Id("siren_type_id"),
+        Rule::Id("contact_id"),
+        Rule::Id("company_id"),
+        Rule::Range("latitude", -90.0, 90.0),
+        Rule::Range("longitude", -180.0, 180.0),
+    ];
+}
+
+impl Validate for SirenCheck {
+    const RULES: &'static [Rule] = &[
+        Rule::Id("siren_id"),
+        Rule::Required("check_date"),
+        Rule::Id("contact_id"),
+    ];
+}
+
+impl Validate for SirenType {
+    const RULES: &'static [Rule] = &[Rule::Required("name"), Rule::Range("radius", 0.0, 1e6)];
+}
+
+impl Validate for User {
+    const RULES: &'static [Rule] = &[
+        Rule::Required("name"),
+        Rule::Required("key"),
+        Rule::Range("role", 0.0, i64::MAX as f64),
+    ];
+}
+
+fn is_blank(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => true,
+        Some(Value::String(text)) => text.trim().is_empty(),
+        _ => false,
+    }
+}
+
+fn as_number(value: Option<&Value>) -> Option<f64> {
+    match value? {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn as_date(value: Option<&Value>) -> Option<NaiveDate> {
+    value?.as_str()?.get(..10)?.parse().ok()
+}
+
+pub fn check(fields: &Map<String, Value>, rules: &[Rule]) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    for rule in rules {
+        match rule {
+            Rule::Required(field) => {
+                if is_blank(fields.get(*field)) {
+                    errors.push(FieldError::new(field, "required", "field is required"));
+                }
+            }
+            Rule::Id(field) => {
+                if let Some(Value::Number(number)) = fields.get(*field) {
+                    if !matches!(number.as_i64(), Some(id) if id > 0) {
+                        errors.push(FieldError::new(field, "invalid_id", "id must be positive"));
+                    }
+                }
+            }
+            Rule::DateOrder(start, end) => {
+                if let (Some(start_date), Some(end_date)) =
+                    (as_date(fields.get(*start)), as_date(fields.get(*end)))
+                {
+                    if end_date < start_date {
+                        errors.push(FieldError::new(
+                            end,
+                            "date_order",
+                            &format!("must not be before {start}"),
+                        ));
+                    }
+                }
+            }
+            Rule::Range(field, min, max) => {
+                if is_blank(fields.get(*field)) {
+                    continue;
+                }
+                match as_number(fields.get(*field)) {
+                    Some(number) if (*min..=*max).contains(&number) => {}
+                    Some(_) => errors.push(FieldError::new(
+                        field,
+                        "out_of_range",
+                        &format!("must be between {min} and {max}"),
+                    )),
+                    None => errors.push(FieldError::new(field, "not_a_number", "must be a number")),
+                }
+            }
+        }
+    }
+    errors
+}
+
+/// Normalizes a phone number to E.164 digits. Numbers without a country code
+/// get `country`, a leading trunk `8` is replaced for Russia.
+pub fn e164(phone: &str, country: &str) -> Option<String> {
+    let international = phone.trim_start().starts_with('+');
+    let digits: String = phone.chars().filter(char::is_ascii_digit).collect();
+    let number = if international {
+        digits
+    } else if country == "7" && digits.len() == 11 && digits.starts_with('8') {
+        format!("7{}", &digits[1..])
+    } else if digits.len() == 10 + country.len() && digits.starts_with(country) {
+        digits
+    } else if digits.len() == 10 {
+        format!("{country}{digits}")
+    } else {
+        return None;
+    };
+    (8..=15).contains(&number.len()).then_some(number)
+}
+
+pub fn is_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && !domain.contains('@')
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !email.chars().any(char::is_whitespace)
+}
+
+fn trim_strings(fields: &mut Map<String, Value>) {
+    for value in fields.values_mut() {
+        match value {
+            Value::String(text) => *text = text.trim().to_string(),
+            Value::Array(items) => {
+                for item in items {
+                    if let Value::String(text) = item {
+                        *text = text.trim().to_string();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Phones keep their JSON type, numbers stay numbers without the `+` sign.
+/// A number that does not normalize is kept as is when it was stored
+/// before, so old local numbers do not block other changes.
+fn normalize_phones(
+    fields: &mut Map<String, Value>,
+    stored: &Map<String, Value>,
+    name: &str,
+    country: &str,
+    errors: &mut Vec<FieldError>,
+) {
+    let Some(Value::Array(phones)) = fields.get_mut(name) else {
+        return;
+    };
+    let stored = match stored.get(name) {
+        Some(Value::Array(stored)) => stored.as_slice(),
+        _ => &[],
+    };
+    phones.retain(|phone| !matches!(phone, Value::String(text) if text.is_empty()));
+    for (index, phone) in phones.iter_mut().enumerate() {
+        let normalized = match phone {
+            Value::Number(number) => e164(&number.to_string(), country)
+                .and_then(|digits| digits.parse::<i64>().ok())
+                .map(Value::from),
+            Value::String(text) => {
+                e164(text, country).map(|digits| Value::from(format!("+{digits}")))
+            }
+            _ => None,
+        };
+        match normalized {
+            Some(value) => *phone = value,
+            None if stored.contains(phone) => {}
+            None => errors.push(FieldError::new(
+                &format!("{name}[{index}]"),
+                "invalid_phone",
+                "invalid phone number",
+            )),
+        }
+    }
+}
+
+fn normalize_emails(fields: &mut Map<String, Value>, errors: &mut Vec<FieldError>) {
+    let Some(Value::Array(emails)) = fields.get_mut("emails") else {
+        return;
+    };
+    emails.retain(|email| !matches!(email, Value::String(text) if text.is_empty()));
+    for (index, email) in emails.iter_mut().enumerate() {
+        match email {
+            Value::String(text) if is_email(text) => *text = text.to_lowercase(),
+            _ => errors.push(FieldError::new(
+                &format!("emails[{index}]"),
+                "invalid_email",
+                "invalid email",
+            )),
+        }
+    }
+}
+
+fn is_normalized(object: &DbObject) -> bool {
+    matches!(
+        object,
+        DbObject::Contact(_) | DbObject::Company(_) | DbObject::Department(_)
+    )
+}
+
+/// Item of an update whose stored state `validate` needs.
+pub fn normalized_item(object: &DbObject) -> Result<Option<Item>, ServiceError> {
+    if !is_normalized(object) {
+        return Ok(None);
+    }
+    Ok(serde_json::to_value(object)?
+        .get(object.name())
+        .and_then(|fields| fields.get("id"))
+        .and_then(Value::as_i64)
+        .map(|id| Item {
+            name: object.name(),
+            id,
+        }))
+}
+
+/// Contact, Company and Department get trimmed strings, E.164 phones and
+/// checked emails; other objects pass as is.
+fn normalize(
+    object: DbObject,
+    stored: Option<&DbObject>,
+    errors: &mut Vec<FieldError>,
+) -> Result<DbObject, ServiceError> {
+    if !is_normalized(&object) {
+        return Ok(object);
+    }
+    let stored = match stored.map(serde_json::to_value).transpose()? {
+        Some(mut value) => match value.get_mut(object.name()).map(Value::take) {
+            Some(Value::Object(fields)) => fields,
+            _ => Map::new(),
+        },
+        None => Map::new(),
+    };
+    let country = config::var_or("RGO_PHONE_COUNTRY", DEFAULT_COUNTRY.to_string());
+    let mut value = serde_json::to_value(&object)?;
+    if let Some(Value::Object(fields)) = value.get_mut(object.name()) {
+        trim_strings(fields);
+        normalize_phones(fields, &stored, "phones", &country, errors);
+        normalize_phones(fields, &stored, "faxes", &country, errors);
+        normalize_emails(fields, errors);
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+impl DbObject {
+    /// No wildcard arm, so a new variant needs rules or a place among the
+    /// read-only ones.
+    pub fn validate(&self) -> Vec<FieldError> {
+        match self {
+            DbObject::Certificate(item) => item.validate(),
+            DbObject::Company(item) => item.validate(),
+            DbObject::CompanyParent(link) => link.validate(),
+            DbObject::Contact(item) => item.validate(),
+            DbObject::Department(item) => item.validate(),
+            DbObject::DepartmentParent(link) => link.validate(),
+            DbObject::Education(item) => item.validate(),
+            DbObject::Kind(item) => item.validate(),
+            DbObject::Post(item) => item.validate(),
+            DbObject::Practice(item) => item.validate(),
+            DbObject::Rank(item) => item.validate(),
+            DbObject::Scope(item) => item.validate(),
+            DbObject::Siren(item) => item.validate(),
+            DbObject::SirenCheck(item) => item.validate(),
+            DbObject::SirenType(item) => item.validate(),
+            DbObject::User(item) => item.validate(),
+            // Replies and uploads, never inserted or updated as items.
+            DbObject::Null
+            | DbObject::Attachment(_)
+            | DbObject::AttachmentList(_)
+            | DbObject::CertificateList(_)
+            | DbObject::CompanyList(_)
+            | DbObject::CompanyTree(_)
+            | DbObject::ContactList(_)
+            | DbObject::ContactBirthday(_)
+            | DbObject::ContactDuplicate(_)
+            | DbObject::Dashboard(_)
+            | DbObject::DepartmentList(_)
+            | DbObject::DepartmentTree(_)
+            | DbObject::EntityVersionList(_)
+            | DbObject::EducationList(_)
+            | DbObject::EducationShort(_)
+            | DbObject::KindList(_)
+            | DbObject::PostList(_)
+            | DbObject::PracticeList(_)
+            | DbObject::PracticeShort(_)
+            | DbObject::RankList(_)
+            | DbObject::ScopeList(_)
+            | DbObject::SelectItem(_)
+            | DbObject::TreePath(_)
+            | DbObject::SirenList(_)
+            | DbObject::SirenCheckList(_)
+            | DbObject::SirenOverdue(_)
+            | DbObject::SirenTypeList(_)
+            | DbObject::UserList(_) => Vec::new(),
+        }
+    }
+}
+
+/// Runs before every insert and update: normalizes input, then checks the
+/// rules of the entity. Updates additionally require a positive `id`,
+/// `stored` is the state before an update of a `normalized_item`.
+pub fn validate(
+    object: DbObject,
+    update: bool,
+    stored: Option<&DbObject>,
+) -> Result<DbObject, ServiceError> {
+    let mut errors = Vec::new();
+    let object = normalize(object, stored, &mut errors)?;
+    errors.extend(object.validate());
+    if update {
+        let id = serde_json::to_value(&object)?
+            .get(object.name())
+            .and_then(|fields| fields.get("id"))
+            .and_then(Value::as_i64);
+        if !matches!(id, Some(id) if id > 0) {
+            errors.push(FieldError::new("id", "invalid_id", "id must be positive"));
+        }
+    }
+    if errors.is_empty() {
+        Ok(object)
+    } else {
+        Err(ServiceError::Validation(errors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn fields(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(fields) => fields,
+            _ => Map::new(),
+        }
+    }
+
+    fn codes(errors: &[FieldError]) -> Vec<(&str, &str)> {
+        errors
+            .iter()
+            .map(|error| (error.field.as_str(), error.code.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn e164_normalizes_russian_numbers() {
+        let expected = Some("79123456789".to_string());
+        assert_eq!(e164("+7 (912) 345-67-89", "7"), expected);
+        assert_eq!(e164("8 912 345 67 89", "7"), expected);
+        assert_eq!(e164("79123456789", "7"), expected);
+        assert_eq!(e164("912-345-67-89", "7"), expected);
+    }
+
+    #[test]
+    fn e164_uses_the_country() {
+        assert_eq!(
+            e164("987 654 32 10", "49"),
+            Some("499876543210".to_string())
+        );
+        assert_eq!(e164("89123456789", "380"), None);
+        assert_eq!(
+            e164("+44 20 7946 0958", "7"),
+            Some("442079460958".to_string())
+        );
+    }
+
+    #[test]
+    fn e164_refuses_short_and_long_numbers() {
+        assert_eq!(e164("12-34", "7"), None);
+        assert_eq!(e164("+123", "7"), None);
+        assert_eq!(e164("+1234567890123456", "7"), None);
+        assert_eq!(e164("", "7"), None);
+    }
+
+    #[test]
+    fn is_email_checks_the_shape() {
+        assert!(is_email("user@example.org"));
+        assert!(is_email("first.last+tag@mail.example.org"));
+        assert!(!is_email("user"));
+        assert!(!is_email("@example.org"));
+        assert!(!is_email("user@example"));
+        assert!(!is_email("user@.example.org"));
+        assert!(!is_email("user@example.org."));
+        assert!(!is_email("user@a@example.org"));
+        assert!(!is_email("us er@example.org"));
+    }
+
+    #[test]
+    fn rules_report_each_field() {
+        let rules = [
+            Rule::Required("name"),
+            Rule::Id("company_id"),
+            Rule::DateOrder("start_date", "end_date"),
+            Rule::Range("latitude", -90.0, 90.0),
+            Rule::Range("radius", 0.0, 10.0),
+        ];
+        let errors = check(
+            &fields(json!({
+                "name": "  ",
+                "company_id": 0,
+                "start_date": "2024-05-02",
+                "end_date": "2024-05-01T00:00:00",
+                "latitude": "91.5",
+                "radius": "far",
+            })),
+            &rules,
+        );
+        assert_eq!(
+            codes(&errors),
+            [
+                ("name", "required"),
+                ("company_id", "invalid_id"),
+                ("end_date", "date_order"),
+                ("latitude", "out_of_range"),
+                ("radius", "not_a_number"),
+            ]
+        );
+        let valid = check(
+            &fields(json!({
+                "name": "Name",
+                "company_id": null,
+                "start_date": "2024-05-01",
+                "end_date": "2024-05-01",
+                "latitude": 55.7,
+                "radius": "",
+            })),
+            &rules,
+        );
+        assert!(valid.is_empty());
+    }
+
+    #[test]
+    fn parent_links_are_validated() {
+        for object in [
+            DbObject::CompanyParent(ParentLink {
+                id: 0,
+                parent_id: Some(-1),
+            }),
+            DbObject::DepartmentParent(ParentLink {
+                id: 0,
+                parent_id: Some(-1),
+            }),
+        ] {
+            assert_eq!(
+                codes(&object.validate()),
+                [("id", "invalid_id"), ("parent_id", "invalid_id")]
+            );
+        }
+        let root = DbObject::CompanyParent(ParentLink {
+            id: 3,
+            parent_id: None,
+        });
+        assert!(validate(root, true, None).is_ok());
+    }
+
+    #[test]
+    fn phones_keep_their_type() {
+        let mut errors = Vec::new();
+        let mut contact = fields(json!({ "phones": [89123456789i64, "8 912 345 67 89", ""] }));
+        normalize_phones(&mut contact, &Map::new(), "phones", "7", &mut errors);
+        assert!(errors.is_empty());
+        assert_eq!(contact["phones"], json!([79123456789i64, "+79123456789"]));
+    }
+
+    #[test]
+    fn stored_invalid_phones_are_kept() {
+        let stored = fields(json!({ "phones": [12345, "ext 12"] }));
+        let mut errors = Vec::new();
+        let mut contact = fields(json!({ "phones": [12345, "ext 12", "67-89"] }));
+        normalize_phones(&mut contact, &stored, "phones", "7", &mut errors);
+        assert_eq!(codes(&errors), [("phones[2]", "invalid_phone")]);
+        assert_eq!(contact["phones"], json!([12345, "ext 12", "67-89"]));
+    }
+
+    #[test]
+    fn emails_are_lowercased_and_checked() {
+        let mut errors = Vec::new();
+        let mut contact = fields(json!({ "emails": ["User@Example.ORG", "", "bad"] }));
+        normalize_emails(&mut contact, &mut errors);
+        assert_eq!(codes(&errors), [("emails[1]", "invalid_email")]);
+        assert_eq!(contact["emails"], json!(["user@example.org", "bad"]));
+    }
+}